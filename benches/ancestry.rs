@@ -0,0 +1,109 @@
+use {
+    criterion::{criterion_group, criterion_main, Criterion},
+    git2::{Oid, Repository, Signature, Time},
+    git_backport::graph::CommitGraph,
+    std::process::Command,
+    tempfile::TempDir,
+};
+
+/// Creates a linear history of `depth` commits, with a short side branch merged back in every 100 commits.
+/// Commit times increase like in real histories, which bounds libgit2's revwalks.
+fn deep_history(depth: usize) -> (TempDir, Oid, Oid) {
+    let directory = TempDir::new().unwrap();
+    let repository = Repository::init(directory.path()).unwrap();
+    let signature = |i: usize| {
+        Signature::new("Bench", "bench@example.com", &Time::new(i as i64 * 60, 0)).unwrap()
+    };
+    let tree = repository
+        .find_tree(repository.treebuilder(None).unwrap().write().unwrap())
+        .unwrap();
+
+    let root = repository
+        .commit(None, &signature(0), &signature(0), "root", &tree, &[])
+        .unwrap();
+    let mut tip = repository.find_commit(root).unwrap();
+    for i in 0..depth {
+        let signature = signature(i + 1);
+        let next = if i % 100 == 99 {
+            let side = repository
+                .commit(None, &signature, &signature, "side", &tree, &[&tip])
+                .unwrap();
+            let side = repository.find_commit(side).unwrap();
+            let main = repository
+                .commit(None, &signature, &signature, "main", &tree, &[&tip])
+                .unwrap();
+            let main = repository.find_commit(main).unwrap();
+            repository
+                .commit(
+                    None,
+                    &signature,
+                    &signature,
+                    "merge",
+                    &tree,
+                    &[&main, &side],
+                )
+                .unwrap()
+        } else {
+            repository
+                .commit(None, &signature, &signature, "commit", &tree, &[&tip])
+                .unwrap()
+        };
+        tip = repository.find_commit(next).unwrap();
+    }
+    let tip = tip.id();
+    (directory, root, tip)
+}
+
+fn ancestry(c: &mut Criterion) {
+    let (directory, root, tip) = deep_history(20_000);
+    let repository = Repository::open(directory.path()).unwrap();
+
+    c.bench_function("is_or_has_ancestor deep", |b| {
+        b.iter(|| {
+            let mut graph = CommitGraph::new(&repository);
+            assert!(graph.is_or_has_ancestor(tip, root).unwrap());
+            assert!(!graph.is_or_has_ancestor(root, tip).unwrap());
+        })
+    });
+
+    c.bench_function("is_or_has_ancestor cached", |b| {
+        let mut graph = CommitGraph::new(&repository);
+        b.iter(|| assert!(graph.is_or_has_ancestor(tip, root).unwrap()))
+    });
+
+    // Like a backport with a short branch on top of the most senior one.
+    let floor = repository
+        .revparse_single(&format!("{}~10", tip))
+        .unwrap()
+        .id();
+    c.bench_function("is_or_has_ancestor above floor", |b| {
+        b.iter(|| {
+            let mut graph = CommitGraph::with_floor(&repository, floor);
+            assert!(graph.is_or_has_ancestor(tip, floor).unwrap());
+            assert!(!graph.is_or_has_ancestor(floor, tip).unwrap());
+        })
+    });
+
+    // Requires the git CLI to write the commit-graph file, which covers commits reachable from refs.
+    repository
+        .branch("master", &repository.find_commit(tip).unwrap(), true)
+        .unwrap();
+    let written = Command::new("git")
+        .args(["commit-graph", "write", "--reachable"])
+        .env("GIT_DIR", repository.path())
+        .status()
+        .is_ok_and(|status| status.success());
+    if written {
+        c.bench_function("is_or_has_ancestor commit-graph", |b| {
+            b.iter(|| {
+                let mut graph = CommitGraph::new(&repository);
+                assert!(graph.has_commit_graph_file());
+                assert!(graph.is_or_has_ancestor(tip, root).unwrap());
+                assert!(!graph.is_or_has_ancestor(root, tip).unwrap());
+            })
+        });
+    }
+}
+
+criterion_group!(benches, ancestry);
+criterion_main!(benches);
@@ -0,0 +1,189 @@
+//! Backporting without rewriting history: Commits moved to more senior branches are cherry-picked onto their tips,
+//! which are then merged forward into the more junior branches.
+//!
+//! See [`BackportArgs::cherry_pick`](crate::BackportArgs::cherry_pick).
+
+use {
+    crate::{
+        drop_empty,
+        editor::{Plan, Problem},
+        fill_template, pick_tree, squash_message, stacking_order, write_tree, EmptyCommits, Error,
+        Messages, Tip,
+    },
+    git2::Commit,
+    log::{info, trace},
+};
+
+/// Changes to the plan that would require rewriting history.
+/// `original_branch_indices` holds the branch each commit was found on, by position.
+pub(crate) fn problems(plan: &Plan, original_branch_indices: &[usize]) -> Vec<Problem> {
+    let moved =
+        |position: usize| plan.commits[position].branch_index > original_branch_indices[position];
+    let mut problems = vec![];
+    for (position, commit) in plan.commits.iter().enumerate() {
+        let (branch_index, original_branch_index) =
+            (commit.branch_index, original_branch_indices[position]);
+        if branch_index >= plan.branches.len() {
+            // Reported by `Plan::validate`.
+            continue;
+        }
+        let message = if branch_index < original_branch_index {
+            format!(
+                "can't be moved from {} to the newer {} without rewriting history",
+                plan.branches[original_branch_index].name, plan.branches[branch_index].name
+            )
+        } else if moved(position) {
+            match commit
+                .fold
+                .and_then(|fold| plan.position(&fold.into.to_string()))
+            {
+                Some(target) if !moved(target) => format!(
+                    "is folded into {}, which isn't cherry-picked",
+                    plan.commits[target].commit.id()
+                ),
+                _ => continue,
+            }
+        } else if commit.fold.is_some() {
+            "can't be folded without rewriting history unless it's cherry-picked".to_string()
+        } else if commit.message.is_some() {
+            "can't be reworded without rewriting history unless it's cherry-picked".to_string()
+        } else {
+            continue;
+        };
+        problems.push(Problem {
+            commit: commit.commit.id(),
+            message,
+        });
+    }
+    problems
+}
+
+/// Appends the moved commits to their branches' tips, oldest first, then merges each changed branch into the next more
+/// junior one and each sibling. Returns the new tips in the order of the plan's branches, followed by the siblings.
+/// `siblings` are paired with the index of their base among the plan's branches followed by the siblings.
+pub(crate) fn apply<'a>(
+    plan: &Plan<'a>,
+    siblings: &[(usize, &Tip<'a>)],
+    original_branch_indices: &[usize],
+    messages: &Messages,
+    empty: EmptyCommits,
+) -> Result<Vec<Commit<'a>>, Error> {
+    let repository = plan.repository;
+    let signature = repository.signature()?;
+
+    let mut heads = plan
+        .branches
+        .iter()
+        .map(|branch| branch.commit.clone())
+        .collect::<Vec<_>>();
+    for position in (0..plan.commits.len()).rev() {
+        let commit = &plan.commits[position];
+        let branch_index = commit.branch_index;
+        if branch_index == original_branch_indices[position] || commit.fold.is_some() {
+            continue;
+        }
+        let branch = &plan.branches[branch_index].name;
+        let id = plan.origin(position);
+
+        let parent_id = match plan.commits.get(position + 1) {
+            Some(parent) => {
+                let id = parent.commit.id();
+                plan.replaces.get(&id).copied().unwrap_or(id)
+            }
+            None => plan.branches.last().unwrap().commit.id(),
+        };
+        // Folded commits are newer, so they come before the commit in the plan.
+        let folded = plan.commits[..position]
+            .iter()
+            .rev()
+            .filter(|folded| folded.fold.map(|fold| fold.into) == Some(commit.commit.id()))
+            .collect::<Vec<_>>();
+
+        info!("Cherrypicking {} onto {}...", id, branch);
+        let tree = pick_tree(
+            repository,
+            &commit.commit,
+            id,
+            parent_id,
+            &folded,
+            &heads[branch_index],
+            plan.merge_strategy,
+            branch,
+        )?;
+
+        if drop_empty(commit, id, tree, &heads[branch_index], empty, branch)? {
+            continue;
+        }
+
+        let cherrypick_commit = repository.commit(
+            None,
+            &commit.commit.author(),
+            &signature,
+            &fill_template(
+                &messages.commit,
+                &[
+                    ("message", &squash_message(commit.message(), &folded)),
+                    ("id", &id.to_string()),
+                ],
+            ),
+            &repository.find_tree(tree)?,
+            &[&heads[branch_index]],
+        )?;
+        heads[branch_index] = repository.find_commit(cherrypick_commit)?;
+    }
+
+    // Merges the new tip `senior` of `from` into `head` of `into`, if `from` changed.
+    let merge_forward = |from: &Tip,
+                         senior: &Commit<'a>,
+                         into: &Tip,
+                         head: &Commit<'a>|
+     -> Result<Commit<'a>, Error> {
+        if senior.id() == from.commit.id() {
+            return Ok(head.clone());
+        }
+        if repository.graph_descendant_of(senior.id(), head.id())? {
+            trace!("Fast-forwarding {}.", into.name);
+            return Ok(senior.clone());
+        }
+        info!("Merging {} into {}...", from.name, into.name);
+        let mut index =
+            repository.merge_commits(head, senior, Some(&plan.merge_strategy.merge_options()))?;
+        let tree = write_tree(repository, &mut index, &from.name, &into.name)?;
+        let merge_commit = repository.commit(
+            None,
+            &signature,
+            &signature,
+            &fill_template(
+                &messages.merge,
+                &[("from", &from.name), ("into", &into.name)],
+            ),
+            &repository.find_tree(tree)?,
+            &[head, senior],
+        )?;
+        Ok(repository.find_commit(merge_commit)?)
+    };
+    for branch_index in (0..heads.len() - 1).rev() {
+        heads[branch_index] = merge_forward(
+            &plan.branches[branch_index + 1],
+            &heads[branch_index + 1],
+            &plan.branches[branch_index],
+            &heads[branch_index],
+        )?;
+    }
+    let tips = plan
+        .branches
+        .iter()
+        .chain(siblings.iter().map(|(_, sibling)| *sibling))
+        .collect::<Vec<_>>();
+    heads.extend(siblings.iter().map(|(_, sibling)| sibling.commit.clone()));
+    for position in stacking_order(siblings, plan.branches.len()) {
+        let (base_index, sibling) = siblings[position];
+        heads[plan.branches.len() + position] = merge_forward(
+            tips[base_index],
+            &heads[base_index],
+            sibling,
+            &sibling.commit,
+        )?;
+    }
+    Ok(heads)
+}
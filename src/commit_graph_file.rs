@@ -0,0 +1,264 @@
+//! Reader for git's commit-graph files (`objects/info/commit-graph` or a split chain in `objects/info/commit-graphs/`).
+//!
+//! See `Documentation/technical/commit-graph-format.txt` in git for the format.
+//! Only SHA-1 graphs are supported. Anything unexpected makes the file be ignored.
+
+use {
+    git2::{Oid, Repository},
+    log::{debug, trace},
+    std::{
+        convert::TryInto,
+        fs,
+        path::{Path, PathBuf},
+    },
+};
+
+const HASH_LENGTH: usize = 20;
+const PARENT_NONE: u32 = 0x7000_0000;
+const PARENT_EXTRA_EDGES: u32 = 0x8000_0000;
+
+struct Layer {
+    data: Vec<u8>,
+    count: usize,
+    fanout: usize,
+    lookup: usize,
+    commit_data: usize,
+    extra_edges: Option<usize>,
+}
+
+/// The layers of a commit-graph, with the base layer first.
+/// Positions are global across all layers.
+pub(crate) struct CommitGraphFile {
+    layers: Vec<Layer>,
+}
+
+impl CommitGraphFile {
+    /// Loads the repository's commit-graph, if there is a usable one.
+    pub(crate) fn open(repository: &Repository) -> Option<Self> {
+        let info = objects_directory(repository).join("info");
+
+        let paths = match fs::read_to_string(info.join("commit-graphs/commit-graph-chain")) {
+            Ok(chain) => chain
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|hash| info.join(format!("commit-graphs/graph-{}.graph", hash)))
+                .collect(),
+            Err(_) => vec![info.join("commit-graph")],
+        };
+
+        let mut layers = vec![];
+        for path in paths {
+            let data = fs::read(&path).ok()?;
+            match Layer::parse(data) {
+                Some(layer) => layers.push(layer),
+                None => {
+                    debug!("Ignoring unsupported commit-graph {}.", path.display());
+                    return None;
+                }
+            }
+        }
+        let file = Self { layers };
+        if !file.is_consistent() {
+            debug!("Ignoring inconsistent commit-graph.");
+            return None;
+        }
+        trace!("Loaded commit-graph with {} commits.", file.count());
+        Some(file)
+    }
+
+    fn count(&self) -> usize {
+        self.layers.iter().map(|layer| layer.count).sum()
+    }
+
+    /// Whether each commit's parents have lower generations than the commit itself.
+    /// The checksum isn't verified, so this keeps walks over a corrupt file from running in cycles.
+    fn is_consistent(&self) -> bool {
+        (0..self.count()).all(|position| {
+            match (self.generation(position), self.parent_positions(position)) {
+                (Some(generation), Some(parents)) => parents.iter().all(|&parent| {
+                    self.generation(parent)
+                        .is_some_and(|parent_generation| parent_generation < generation)
+                }),
+                _ => false,
+            }
+        })
+    }
+
+    fn layer(&self, mut position: usize) -> Option<(&Layer, usize)> {
+        for layer in &self.layers {
+            if position < layer.count {
+                return Some((layer, position));
+            }
+            position -= layer.count;
+        }
+        None
+    }
+
+    /// Finds the global position of a commit.
+    pub(crate) fn position(&self, id: Oid) -> Option<usize> {
+        let mut offset = 0;
+        for layer in &self.layers {
+            if let Some(position) = layer.position(id) {
+                return Some(offset + position);
+            }
+            offset += layer.count;
+        }
+        None
+    }
+
+    pub(crate) fn id(&self, position: usize) -> Option<Oid> {
+        let (layer, position) = self.layer(position)?;
+        Oid::from_bytes(layer.hash(position)?).ok()
+    }
+
+    /// The topological level of the commit at `position`, or `None` if it wasn't computed.
+    pub(crate) fn generation(&self, position: usize) -> Option<u32> {
+        let (layer, position) = self.layer(position)?;
+        let generation = layer.commit_word(position, HASH_LENGTH + 8)? >> 2;
+        if generation == 0 {
+            None
+        } else {
+            Some(generation)
+        }
+    }
+
+    pub(crate) fn parents(&self, position: usize) -> Option<Vec<Oid>> {
+        self.parent_positions(position)?
+            .into_iter()
+            .map(|position| self.id(position))
+            .collect()
+    }
+
+    fn parent_positions(&self, position: usize) -> Option<Vec<usize>> {
+        let (layer, local) = self.layer(position)?;
+        let mut parent_positions = vec![];
+        match layer.commit_word(local, HASH_LENGTH)? {
+            PARENT_NONE => (),
+            first => parent_positions.push(first),
+        }
+        match layer.commit_word(local, HASH_LENGTH + 4)? {
+            PARENT_NONE => (),
+            second if second & PARENT_EXTRA_EDGES != 0 => {
+                let mut edge = layer
+                    .extra_edges?
+                    .checked_add((second & !PARENT_EXTRA_EDGES) as usize * 4)?;
+                loop {
+                    let value = read_u32(&layer.data, edge)?;
+                    parent_positions.push(value & !PARENT_EXTRA_EDGES);
+                    if value & PARENT_EXTRA_EDGES != 0 {
+                        break;
+                    }
+                    edge += 4;
+                }
+            }
+            second => parent_positions.push(second),
+        }
+        Some(
+            parent_positions
+                .into_iter()
+                .map(|position| position as usize)
+                .collect(),
+        )
+    }
+}
+
+impl Layer {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        if data.get(0..4)? != b"CGPH" || *data.get(4)? != 1 || *data.get(5)? != 1 {
+            return None;
+        }
+        let chunk_count = *data.get(6)? as usize;
+
+        let (mut fanout, mut lookup, mut commit_data, mut extra_edges) = (None, None, None, None);
+        for i in 0..chunk_count {
+            let entry = 8 + i * 12;
+            let offset = read_u64(&data, entry + 4)? as usize;
+            match data.get(entry..entry + 4)? {
+                b"OIDF" => fanout = Some(offset),
+                b"OIDL" => lookup = Some(offset),
+                b"CDAT" => commit_data = Some(offset),
+                b"EDGE" => extra_edges = Some(offset),
+                _ => (),
+            }
+        }
+        let fanout = fanout?;
+        let count = read_u32(&data, fanout + 255 * 4)? as usize;
+        let layer = Self {
+            count,
+            fanout,
+            lookup: lookup?,
+            commit_data: commit_data?,
+            extra_edges,
+            data,
+        };
+        // Offsets come from the file, so they may overflow.
+        if layer.data.len() < layer.lookup.checked_add(count * HASH_LENGTH)?
+            || layer.data.len() < layer.commit_data.checked_add(count * (HASH_LENGTH + 16))?
+        {
+            return None;
+        }
+        Some(layer)
+    }
+
+    fn position(&self, id: Oid) -> Option<usize> {
+        let first_byte = id.as_bytes()[0] as usize;
+        let start = if first_byte == 0 {
+            0
+        } else {
+            read_u32(&self.data, self.fanout + (first_byte - 1) * 4)? as usize
+        };
+        let end = read_u32(&self.data, self.fanout + first_byte * 4)? as usize;
+
+        // A corrupt fanout table may point past the lookup table.
+        let (mut low, mut high) = (start, end.min(self.count));
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.hash(middle)?.cmp(id.as_bytes()) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    fn hash(&self, position: usize) -> Option<&[u8]> {
+        let start = self.lookup + position * HASH_LENGTH;
+        self.data.get(start..start + HASH_LENGTH)
+    }
+
+    fn commit_word(&self, position: usize, offset: usize) -> Option<u32> {
+        read_u32(
+            &self.data,
+            self.commit_data + position * (HASH_LENGTH + 16) + offset,
+        )
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset.checked_add(8)?)?.try_into().ok()?,
+    ))
+}
+
+/// Linked worktrees keep their objects in the main repository.
+fn objects_directory(repository: &Repository) -> PathBuf {
+    let path = repository.path();
+    let common: PathBuf = fs::read_to_string(path.join("commondir"))
+        .map(|common| {
+            let common = Path::new(common.trim());
+            if common.is_absolute() {
+                common.to_path_buf()
+            } else {
+                path.join(common)
+            }
+        })
+        .unwrap_or_else(|_| path.to_path_buf());
+    common.join("objects")
+}
@@ -0,0 +1,145 @@
+//! Named branch stacks persisted in git config, e.g.:
+//!
+//! ```ini
+//! [backport "stack.release"]
+//!     branches = develop release/2.x release/1.x
+//!     backup = false
+//!     mergeStrategy = normal
+//!     mergeMessage = Merge {from} into {into}
+//!     commitMessage = {message}
+//!     cherryPick = false
+//! ```
+//!
+//! `branches` may be given multiple times and lists branches from most junior to most senior.
+
+use {
+    crate::{editor::Editor, BackportArgs, Error, MergeStrategy, Messages, Tip},
+    git2::{Config, ErrorCode, Repository},
+};
+
+pub struct Stack {
+    pub name: String,
+    /// From most junior to most senior.
+    pub branches: Vec<String>,
+    pub backup: Option<bool>,
+    pub merge_strategy: Option<MergeStrategy>,
+    pub merge_message: Option<String>,
+    pub commit_message: Option<String>,
+    /// See [`BackportArgs::cherry_pick`].
+    pub cherry_pick: Option<bool>,
+}
+
+impl Stack {
+    pub fn load(config: &Config, name: &str) -> Result<Self, Error> {
+        let key = |variable: &str| format!("backport.stack.{}.{}", name, variable);
+
+        let mut branches = vec![];
+        for entry in &config.entries(Some(&format!(
+            "^backport\\.stack\\.{}\\.branches$",
+            escape_regex(name)
+        )))? {
+            let entry = entry?;
+            branches.extend(
+                entry
+                    .value()
+                    .ok_or_else(|| Error::InvalidConfig {
+                        key: key("branches"),
+                        value: String::from_utf8_lossy(entry.value_bytes()).into_owned(),
+                    })?
+                    .split_whitespace()
+                    .map(str::to_string),
+            )
+        }
+        if branches.is_empty() {
+            return Err(Error::UnknownStack(name.to_string()));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            branches,
+            backup: optional(config.get_bool(&key("backup")))?,
+            merge_strategy: optional(config.get_string(&key("mergestrategy")))?
+                .map(|value| {
+                    value.parse().map_err(|_| Error::InvalidConfig {
+                        key: key("mergeStrategy"),
+                        value,
+                    })
+                })
+                .transpose()?,
+            merge_message: optional(config.get_string(&key("mergemessage")))?,
+            commit_message: optional(config.get_string(&key("commitmessage")))?,
+            cherry_pick: optional(config.get_bool(&key("cherrypick")))?,
+        })
+    }
+
+    /// Resolves the stack's branches that are more senior than `head`.
+    /// If `head` isn't part of the stack, all its branches are returned.
+    pub fn ancestors<'a>(
+        &self,
+        repository: &'a Repository,
+        head: &Tip,
+    ) -> Result<Vec<Tip<'a>>, Error> {
+        let skip = head
+            .branch
+            .as_ref()
+            .and_then(|head| self.branches.iter().position(|branch| branch == head))
+            .map_or(0, |position| position + 1);
+        self.branches[skip..]
+            .iter()
+            .map(|branch| Tip::resolve_branch(repository, branch).map_err(Error::from))
+            .collect()
+    }
+
+    pub fn messages(&self) -> Messages {
+        let defaults = Messages::default();
+        Messages {
+            merge: self.merge_message.clone().unwrap_or(defaults.merge),
+            commit: self.commit_message.clone().unwrap_or(defaults.commit),
+        }
+    }
+
+    /// Creates [`BackportArgs`] with this stack's defaults.
+    pub fn backport_args<'a, E: Editor>(
+        &self,
+        repository: &'a Repository,
+        branches: &'a [Tip<'a>],
+        editor: E,
+    ) -> BackportArgs<'a, E> {
+        BackportArgs {
+            repository,
+            backup: self.backup.unwrap_or(true),
+            branches,
+            siblings: &[],
+            merge_strategy: self.merge_strategy.unwrap_or_default(),
+            messages: self.messages(),
+            parent_preference: Default::default(),
+            verify: false,
+            autosquash: false,
+            cherry_pick: self.cherry_pick.unwrap_or(false),
+            empty: Default::default(),
+            update_refs: false,
+            update_tags: false,
+            editor,
+        }
+    }
+}
+
+fn optional<T>(result: Result<T, git2::Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(error) if error.code() == ErrorCode::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| {
+            if "\\.+*?()|[]{}^$".contains(c) {
+                vec!['\\', c]
+            } else {
+                vec![c]
+            }
+        })
+        .collect()
+}
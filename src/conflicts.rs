@@ -0,0 +1,98 @@
+//! Predicts which commits of a [`Plan`] won't apply cleanly, without touching the repository.
+
+use {
+    crate::editor::Plan,
+    git2::{Error, Oid, Repository},
+    std::{collections::HashMap, path::PathBuf},
+};
+
+/// Replays plans as trees in memory.
+pub struct ConflictPredictor {
+    /// The repository's git directory.
+    path: PathBuf,
+}
+
+impl ConflictPredictor {
+    pub fn new(repository: &Repository) -> Result<Self, Error> {
+        let predictor = Self {
+            path: repository.path().to_path_buf(),
+        };
+        predictor.scratch()?;
+        Ok(predictor)
+    }
+
+    /// Opens another handle to the repository that writes objects to memory, while reading existing ones from disk.
+    /// The written trees are discarded along with it, since merging requires them as objects.
+    fn scratch(&self) -> Result<Repository, Error> {
+        let scratch = Repository::open(&self.path)?;
+        // Takes precedence over the loose and packed backends for writes.
+        scratch.odb()?.add_new_mempack_backend(1000)?;
+        Ok(scratch)
+    }
+
+    /// For each commit of the plan, the most senior branch it conflicts on, if any.
+    ///
+    /// Each commit's changes are applied to its branch and, standing in for catch-up merges, to every more junior one,
+    /// oldest commit first and starting from the most senior tip. Folded commits are applied right after the commit
+    /// they're folded into.
+    pub fn predict(&self, plan: &Plan) -> Result<Vec<Option<usize>>, Error> {
+        let repository = &self.scratch()?;
+        let senior = plan.branches.last().unwrap().commit.id();
+        let mut trees = vec![repository.find_commit(senior)?.tree_id(); plan.branches.len()];
+        let mut conflicts = vec![None; plan.commits.len()];
+        let options = plan.merge_strategy.merge_options();
+
+        let mut folded = HashMap::<_, Vec<_>>::new();
+        for position in (0..plan.commits.len()).rev() {
+            if let Some(fold) = plan.commits[position].fold {
+                folded.entry(fold.into).or_default().push(position);
+            }
+        }
+
+        for position in (0..plan.commits.len()).rev() {
+            let commit = &plan.commits[position];
+            if commit.fold.is_some() {
+                continue;
+            }
+            let branch_index = commit.branch_index.min(plan.branches.len() - 1);
+            let folded = folded
+                .get(&commit.commit.id())
+                .map_or(&[][..], Vec::as_slice);
+            for &position in [position].iter().chain(folded) {
+                let parent_id = plan
+                    .commits
+                    .get(position + 1)
+                    .map_or(senior, |parent| parent.commit.id());
+                let base = repository.find_commit(parent_id)?.tree()?;
+                let theirs = repository.find_tree(plan.commits[position].commit.tree_id())?;
+                for (tree_branch_index, tree) in trees[..=branch_index].iter_mut().enumerate().rev()
+                {
+                    match apply(repository, &base, *tree, &theirs, &options)? {
+                        Some(applied) => *tree = applied,
+                        None => {
+                            conflicts[position] = conflicts[position].or(Some(tree_branch_index));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(conflicts)
+    }
+}
+
+/// Applies the changes from `base` to `theirs` onto `ours`, or returns `None` if they conflict.
+fn apply(
+    repository: &Repository,
+    base: &git2::Tree,
+    ours: Oid,
+    theirs: &git2::Tree,
+    options: &git2::MergeOptions,
+) -> Result<Option<Oid>, Error> {
+    let ours = repository.find_tree(ours)?;
+    let mut index = repository.merge_trees(base, &ours, theirs, Some(options))?;
+    if index.has_conflicts() {
+        Ok(None)
+    } else {
+        Ok(Some(index.write_tree_to(repository)?))
+    }
+}
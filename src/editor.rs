@@ -0,0 +1,325 @@
+//! Assignment of commits to branches before history is rewritten.
+//!
+//! [`backport`](crate::backport) hands a [`Plan`] to an [`Editor`], validates the result and hands it back with the
+//! problems found until the plan is valid and confirmed, or the editor cancels.
+
+use {
+    crate::{
+        split::{self, Hunk},
+        BackportCommit, Error, Fold, FoldKind, MergeStrategy, ParentCandidate, Tip,
+    },
+    core::fmt::{self, Display, Formatter},
+    git2::{Commit, Oid, Repository},
+    std::collections::HashMap,
+};
+
+/// Errors raised by editors themselves, e.g. when an external program fails.
+pub type EditorError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The commits to backport and the branches they are assigned to.
+pub struct Plan<'a> {
+    pub repository: &'a Repository,
+    /// From most junior to most senior.
+    pub branches: &'a [Tip<'a>],
+    /// How commits will be applied.
+    pub merge_strategy: MergeStrategy,
+    /// Newest first. Each is initially assigned to the branch it was found on.
+    pub commits: Vec<BackportCommit<'a>>,
+    /// For each commit, the positions in `commits` its side chains were forked from.
+    pub(crate) forks: Vec<Vec<usize>>,
+    /// The commit each part of a split commit was split off from.
+    pub(crate) origins: HashMap<Oid, Oid>,
+    /// For the newest part of a split commit, the commit it takes the place of in history.
+    pub(crate) replaces: HashMap<Oid, Oid>,
+}
+
+impl<'a> Plan<'a> {
+    /// Positions in `commits` of the commits that the side chains merged by the commit at `position` were forked from.
+    pub fn fork_points(&self, position: usize) -> &[usize] {
+        &self.forks[position]
+    }
+
+    /// Finds a commit by full id or unique prefix.
+    pub fn position(&self, id: &str) -> Option<usize> {
+        let mut matches = self
+            .commits
+            .iter()
+            .enumerate()
+            .filter(|(_, commit)| commit.commit.id().to_string().starts_with(id))
+            .map(|(position, _)| position);
+        match (matches.next(), matches.next()) {
+            (Some(position), None) if !id.is_empty() => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Finds a branch by name or by its index.
+    pub fn branch_index(&self, name: &str) -> Option<usize> {
+        self.branches
+            .iter()
+            .position(|branch| branch.name == name)
+            .or_else(|| name.parse().ok().filter(|&i| i < self.branches.len()))
+    }
+
+    /// The commit the commit at `position` was split off from, or its own id.
+    pub fn origin(&self, position: usize) -> Oid {
+        let id = self.commits[position].commit.id();
+        self.origins.get(&id).copied().unwrap_or(id)
+    }
+
+    /// The hunks of the commit at `position`, assigned to its branch.
+    pub fn hunks(&self, position: usize) -> Result<Vec<Hunk>, Error> {
+        let commit = &self.commits[position];
+        if commit.commit.parent_count() != 1 {
+            return Err(Error::Unsplittable(commit.commit.id()));
+        }
+        Ok(split::hunks(
+            self.repository,
+            &commit.commit,
+            commit.branch_index,
+        )?)
+    }
+
+    /// Splits the commit at `position` into one part per branch its `hunks` are assigned to.
+    ///
+    /// The parts take its place, with the most senior part applied first and the newest part ending up with the
+    /// commit's tree. If all hunks are assigned to the same branch, the commit is just moved there.
+    pub fn split(&mut self, position: usize, hunks: &[Hunk]) -> Result<(), Error> {
+        let commit = self.commits[position].commit.clone();
+        if commit.parent_count() != 1 {
+            return Err(Error::Unsplittable(commit.id()));
+        }
+        let mut branch_indices = hunks.iter().map(|h| h.branch_index).collect::<Vec<_>>();
+        branch_indices.sort_unstable();
+        branch_indices.dedup();
+        if let [branch_index] = branch_indices[..] {
+            self.commits[position].branch_index = branch_index;
+            return Ok(());
+        }
+
+        let repository = self.repository;
+        let origin = self.origin(position);
+        let signature = repository.signature()?;
+        let mut parent = commit.parent(0)?;
+        let mut parts = vec![];
+        for (i, &branch_index) in branch_indices.iter().enumerate().rev() {
+            let tree = if i == 0 {
+                commit.tree()?
+            } else {
+                repository.find_tree(split::apply(repository, &commit, hunks, |hunk| {
+                    hunk.branch_index >= branch_index
+                })?)?
+            };
+            let part = repository.find_commit(repository.commit(
+                None,
+                &commit.author(),
+                &signature,
+                self.commits[position].message(),
+                &tree,
+                &[&parent],
+            )?)?;
+            self.origins.insert(part.id(), origin);
+            parent = part.clone();
+            parts.push(BackportCommit {
+                commit: part,
+                branch_index,
+                fold: self.commits[position].fold,
+                // Already part of the commit.
+                message: None,
+                duplicate: self.commits[position].duplicate,
+            });
+        }
+        let commit_id = commit.id();
+        if let Some(replaced) = self.replaces.remove(&commit.id()) {
+            self.replaces.insert(parent.id(), replaced);
+        } else {
+            self.replaces.insert(parent.id(), commit.id());
+        }
+
+        for commit in &mut self.commits {
+            if let Some(fold) = &mut commit.fold {
+                if fold.into == commit_id {
+                    fold.into = parent.id();
+                }
+            }
+        }
+
+        // Newest first, like the plan.
+        parts.reverse();
+        let added = parts.len() - 1;
+        self.commits.splice(position..=position, parts);
+        for fork_points in &mut self.forks {
+            for fork in fork_points.iter_mut().filter(|fork| **fork > position) {
+                *fork += added;
+            }
+        }
+        // Side chains forked from the commit see all of it, i.e. the newest part, which stays at `position`.
+        self.forks
+            .splice(position + 1..position + 1, vec![vec![]; added]);
+        Ok(())
+    }
+
+    /// Folds each commit with a `fixup! ` or `squash! ` subject into the nearest earlier commit whose subject starts
+    /// with the rest of it, or whose id does, like `git rebase --autosquash`. It's moved to that commit's branch.
+    pub fn autosquash(&mut self) {
+        for position in (0..self.commits.len()).rev() {
+            let summary = self.commits[position].commit.summary().unwrap_or_default();
+            let (kind, mut target) = if let Some(target) = summary.strip_prefix("fixup! ") {
+                (FoldKind::Fixup, target)
+            } else if let Some(target) = summary.strip_prefix("squash! ") {
+                (FoldKind::Squash, target)
+            } else {
+                continue;
+            };
+            while let Some(nested) = target
+                .strip_prefix("fixup! ")
+                .or_else(|| target.strip_prefix("squash! "))
+            {
+                target = nested;
+            }
+            let found = (position + 1..self.commits.len()).find(|&earlier| {
+                let commit = &self.commits[earlier].commit;
+                commit.summary().unwrap_or_default().starts_with(target)
+                    || (target.len() >= 4 && commit.id().to_string().starts_with(target))
+            });
+            if let Some(mut earlier) = found {
+                // Commits folded into folded ones end up in the same commit.
+                while let Some(fold) = self.commits[earlier].fold {
+                    earlier = self.position(&fold.into.to_string()).unwrap();
+                }
+                let into = &self.commits[earlier];
+                let (into, branch_index) = (into.commit.id(), into.branch_index);
+                let commit = &mut self.commits[position];
+                commit.fold = Some(Fold { kind, into });
+                commit.branch_index = branch_index;
+            }
+        }
+    }
+
+    /// Lists why the plan can't be carried out, if it can't.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        for (position, commit) in self.commits.iter().enumerate() {
+            if commit.branch_index >= self.branches.len() {
+                problems.push(Problem {
+                    commit: commit.commit.id(),
+                    message: format!(
+                        "assigned to branch {}, but there are only {}",
+                        commit.branch_index,
+                        self.branches.len()
+                    ),
+                });
+                continue;
+            }
+            if let Some(fold) = commit.fold {
+                let problem = match self.position(&fold.into.to_string()) {
+                    Some(into) if into > position => match &self.commits[into] {
+                        into if into.fold.is_some() => Some("which is folded itself".to_string()),
+                        into if into.branch_index != commit.branch_index => Some(format!(
+                            "which is on {}",
+                            self.branches
+                                .get(into.branch_index)
+                                .map_or("another branch", |branch| &branch.name)
+                        )),
+                        _ if commit.commit.parent_count() != 1 => {
+                            Some("but merge commits can't be folded".to_string())
+                        }
+                        _ => None,
+                    },
+                    _ => Some("which isn't an earlier commit of the backport".to_string()),
+                };
+                if let Some(problem) = problem {
+                    problems.push(Problem {
+                        commit: commit.commit.id(),
+                        message: format!("is folded into {}, {}", fold.into, problem),
+                    });
+                }
+            }
+            for &fork in self.fork_points(position) {
+                let fork = &self.commits[fork];
+                if fork.branch_index < commit.branch_index {
+                    problems.push(Problem {
+                        commit: commit.commit.id(),
+                        message: format!(
+                            "merges a side chain forked from {}, which must be on {} or more senior",
+                            fork.commit.id(),
+                            self.branches[commit.branch_index].name
+                        ),
+                    })
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// A reason a [`Plan`] can't be carried out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub commit: Oid,
+    pub message: String,
+}
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.commit, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditOutcome {
+    /// Validate the plan and, if it's valid, carry it out.
+    Apply,
+    /// Stop without modifying anything.
+    Cancel,
+}
+
+pub trait Editor {
+    /// Assigns the plan's commits to branches.
+    /// `problems` lists why the previous edit was rejected and is empty on the first call.
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError>;
+
+    /// Called with a valid plan before any commits are created. Returning `false` goes back to editing.
+    fn confirm(&mut self, _plan: &Plan) -> Result<bool, EditorError> {
+        Ok(true)
+    }
+
+    /// Called when the ancestry of a merge commit is ambiguous.
+    /// Returns an index into the candidates, or `None` to fall back to the [`ParentPreference`](crate::ParentPreference).
+    fn choose_parent(
+        &mut self,
+        _commit: &Commit,
+        _candidates: &[ParentCandidate],
+    ) -> Result<Option<usize>, EditorError> {
+        Ok(None)
+    }
+}
+
+/// Carries out the plan as it was handed over, e.g. as assigned by `Backport-To:` trailers.
+pub struct Accept;
+
+impl Editor for Accept {
+    fn edit(&mut self, _plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        match problems.first() {
+            Some(problem) => Err(format!("The plan is invalid: {}", problem).into()),
+            None => Ok(EditOutcome::Apply),
+        }
+    }
+}
+
+impl<E: Editor + ?Sized> Editor for &mut E {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        (**self).edit(plan, problems)
+    }
+
+    fn confirm(&mut self, plan: &Plan) -> Result<bool, EditorError> {
+        (**self).confirm(plan)
+    }
+
+    fn choose_parent(
+        &mut self,
+        commit: &Commit,
+        candidates: &[ParentCandidate],
+    ) -> Result<Option<usize>, EditorError> {
+        (**self).choose_parent(commit, candidates)
+    }
+}
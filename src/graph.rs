@@ -0,0 +1,195 @@
+//! Iterative ancestry queries over a shared parent cache.
+//!
+//! Walks are pruned by generation numbers: A commit can only reach commits with a lower generation.
+//! These are read from git's commit-graph file where present and computed on the fly otherwise.
+//! Without a commit-graph file, a graph with a floor only numbers the commits that aren't ancestors of the floor,
+//! so that computing them stops at merge bases with it instead of walking all of history.
+
+use {
+    crate::commit_graph_file::CommitGraphFile,
+    git2::{Error, Oid, Repository, Sort},
+    std::collections::{HashMap, HashSet},
+};
+
+struct Node {
+    parents: Vec<Oid>,
+    generation: Option<u32>,
+}
+
+/// Caches parent lists, generation numbers and ancestry results for the duration of a backport.
+pub struct CommitGraph<'a> {
+    repository: &'a Repository,
+    file: Option<CommitGraphFile>,
+    /// Generations count from 0 at this commit and its ancestors if there's no `file`.
+    floor: Option<Oid>,
+    nodes: HashMap<Oid, Node>,
+    ancestry: HashMap<(Oid, Oid), bool>,
+}
+
+impl<'a> CommitGraph<'a> {
+    pub fn new(repository: &'a Repository) -> Self {
+        Self {
+            repository,
+            file: CommitGraphFile::open(repository),
+            floor: None,
+            nodes: HashMap::new(),
+            ancestry: HashMap::new(),
+        }
+    }
+
+    /// Like [`new`](Self::new), for queries about commits that aren't ancestors of `floor`.
+    /// Those give it and its ancestors a generation of 0, unless there's a commit-graph file.
+    pub fn with_floor(repository: &'a Repository, floor: Oid) -> Self {
+        let mut graph = Self::new(repository);
+        if graph.file.is_none() {
+            graph.floor = Some(floor);
+        }
+        graph
+    }
+
+    /// Whether the repository's commit-graph file is used.
+    pub fn has_commit_graph_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn node(&mut self, id: Oid) -> Result<&mut Node, Error> {
+        if !self.nodes.contains_key(&id) {
+            let from_file = self.file.as_ref().and_then(|file| {
+                let position = file.position(id)?;
+                Some(Node {
+                    parents: file.parents(position)?,
+                    generation: file.generation(position),
+                })
+            });
+            let node = match from_file {
+                Some(node) => node,
+                None => Node {
+                    parents: self.repository.find_commit(id)?.parent_ids().collect(),
+                    generation: None,
+                },
+            };
+            self.nodes.insert(id, node);
+        }
+        Ok(self.nodes.get_mut(&id).unwrap())
+    }
+
+    pub fn parents(&mut self, id: Oid) -> Result<&[Oid], Error> {
+        Ok(&self.node(id)?.parents)
+    }
+
+    /// The commit's topological level: 1 for root commits, otherwise one more than that of its highest parent.
+    /// With a floor, that's 0 for the floor and its ancestors instead.
+    pub fn generation(&mut self, id: Oid) -> Result<u32, Error> {
+        if let Some(floor) = self.floor {
+            return self.generation_above(id, floor);
+        }
+        let mut stack = vec![id];
+        while let Some(&top) = stack.last() {
+            let node = self.node(top)?;
+            if node.generation.is_some() {
+                stack.pop();
+                continue;
+            }
+            let parents = node.parents.clone();
+            let mut pending = false;
+            let mut generation = 1;
+            for parent in parents {
+                match self.node(parent)?.generation {
+                    Some(parent_generation) => generation = generation.max(parent_generation + 1),
+                    None => {
+                        pending = true;
+                        stack.push(parent)
+                    }
+                }
+            }
+            if !pending {
+                self.node(top)?.generation = Some(generation);
+                stack.pop();
+            }
+        }
+        Ok(self.node(id)?.generation.unwrap())
+    }
+
+    fn generation_above(&mut self, id: Oid, floor: Oid) -> Result<u32, Error> {
+        if let Some(generation) = self.node(id)?.generation {
+            return Ok(generation);
+        }
+        // Only walks down to the merge bases with `floor`.
+        let mut revwalk = self.repository.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(id)?;
+        revwalk.hide(floor)?;
+        let above = revwalk.collect::<Result<Vec<_>, Error>>()?;
+        let above_set = above.iter().copied().collect::<HashSet<_>>();
+        for commit in above {
+            if self.node(commit)?.generation.is_some() {
+                continue;
+            }
+            let mut generation = 1;
+            for parent in self.node(commit)?.parents.clone() {
+                if above_set.contains(&parent) {
+                    generation = generation.max(self.node(parent)?.generation.unwrap() + 1);
+                } else {
+                    self.node(parent)?.generation = Some(0);
+                }
+            }
+            self.node(commit)?.generation = Some(generation);
+        }
+        let node = self.node(id)?;
+        Ok(*node.generation.get_or_insert(0))
+    }
+
+    /// Whether `ancestor` is `descendant` itself or reachable from it.
+    pub fn is_or_has_ancestor(&mut self, descendant: Oid, ancestor: Oid) -> Result<bool, Error> {
+        if descendant == ancestor {
+            return Ok(true);
+        }
+        if let Some(&known) = self.ancestry.get(&(descendant, ancestor)) {
+            return Ok(known);
+        }
+
+        let floor = self.generation(ancestor)?;
+        let mut found = false;
+        if floor == 0 && self.floor != Some(ancestor) {
+            // Paths to ancestors of the floor may lead through other ones, which aren't told apart.
+            found = self.repository.graph_descendant_of(descendant, ancestor)?;
+        } else {
+            self.walk(descendant, floor, |id| {
+                found |= id == ancestor;
+                found
+            })?;
+        }
+        self.ancestry.insert((descendant, ancestor), found);
+        Ok(found)
+    }
+
+    /// Walks the commits reachable from `from` depth-first, without descending into commits with a generation of at most `floor`
+    /// or for which `stop` returns `true`.
+    ///
+    /// Returns the commits descended into and, separately, the ones `stop` returned `true` for.
+    pub fn walk(
+        &mut self,
+        from: Oid,
+        floor: u32,
+        mut stop: impl FnMut(Oid) -> bool,
+    ) -> Result<(Vec<Oid>, Vec<Oid>), Error> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        let (mut walked, mut stopped) = (vec![], vec![]);
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            if stop(id) {
+                stopped.push(id);
+                continue;
+            }
+            if self.generation(id)? <= floor {
+                continue;
+            }
+            walked.push(id);
+            stack.extend(self.parents(id)?.iter().rev());
+        }
+        Ok((walked, stopped))
+    }
+}
@@ -0,0 +1,56 @@
+//! Lets a signal handler cancel a running [`backport`](crate::backport) for as long as no branches were modified.
+//!
+//! Once the backport starts updating references, exiting would leave them half-updated,
+//! so interrupts are only recorded from then on.
+
+use {
+    crate::Error,
+    std::sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+const RUNNING: u8 = 0;
+const UPDATING: u8 = 1;
+const INTERRUPTED: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(RUNNING);
+static INTERRUPTED_WHILE_UPDATING: AtomicBool = AtomicBool::new(false);
+
+/// Requests cancellation. Returns `true` if no references were updated yet, in which case none will be and the
+/// process can exit right away. Otherwise returns `false`, and the backport finishes updating them.
+pub fn request() -> bool {
+    match STATE.compare_exchange(RUNNING, INTERRUPTED, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) | Err(INTERRUPTED) => true,
+        Err(_) => {
+            INTERRUPTED_WHILE_UPDATING.store(true, Ordering::SeqCst);
+            false
+        }
+    }
+}
+
+/// Whether [`request`] was called after references started being updated.
+pub fn requested_while_updating() -> bool {
+    INTERRUPTED_WHILE_UPDATING.load(Ordering::SeqCst)
+}
+
+/// Resets the state once a backport returns, so the next one in the same process can be interrupted again.
+pub(crate) struct Run;
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        STATE.store(RUNNING, Ordering::SeqCst);
+    }
+}
+
+/// Marks the start of a backport. An interrupt requested before still cancels it.
+pub(crate) fn start() -> Run {
+    INTERRUPTED_WHILE_UPDATING.store(false, Ordering::SeqCst);
+    Run
+}
+
+/// Marks the start of reference updates, or fails with [`Error::Canceled`] if an interrupt came first.
+pub(crate) fn begin_updates() -> Result<(), Error> {
+    match STATE.compare_exchange(RUNNING, UPDATING, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) | Err(UPDATING) => Ok(()),
+        Err(_) => Err(Error::Canceled),
+    }
+}
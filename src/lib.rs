@@ -1,49 +1,611 @@
-#![allow(unreachable_code)]
-
 use {
-    core::{
-        cell::RefCell,
-        fmt::{self, Formatter},
+    crate::{
+        editor::{EditOutcome, Editor, EditorError, Plan},
+        graph::CommitGraph,
+    },
+    core::fmt::{self, Formatter},
+    git2::{
+        Branch, BranchType, Commit, FileFavor, Index, MergeOptions, ObjectType, Oid, Repository,
+        Signature,
     },
-    git2::{Branch, Commit, MergeOptions, Oid, Repository},
-    log::{info, trace},
+    log::{info, trace, warn},
     std::{
         borrow::Cow,
         collections::{HashMap, HashSet},
+        str::FromStr,
     },
 };
 
+mod cherry_pick;
+mod commit_graph_file;
+pub mod config;
+pub mod conflicts;
+pub mod editor;
+pub mod graph;
+pub mod interrupt;
+mod patch_id;
+pub mod rules;
+pub mod split;
+pub mod todo;
+
 #[derive(Debug)]
-pub enum Error {}
-impl std::error::Error for Error {}
+pub enum Error {
+    Git(git2::Error),
+    /// `ancestor` isn't reachable from `descendant`, so the branch chain is out of order.
+    UnorderedChain {
+        descendant: String,
+        ancestor: String,
+    },
+    /// The rewritten `branch` failed verification. No branches were modified.
+    VerificationFailed {
+        branch: String,
+        reason: &'static str,
+    },
+    UnknownStack(String),
+    InvalidConfig {
+        key: String,
+        value: String,
+    },
+    Editor(EditorError),
+    /// Only commits with a single parent can be split.
+    Unsplittable(Oid),
+    /// Applying `from`, a commit or branch, to the branch `into` conflicts. No branches were modified.
+    Conflict {
+        from: String,
+        into: String,
+    },
+    /// `commit` would become empty on `branch` with [`EmptyCommits::Stop`]. No branches were modified.
+    Empty {
+        commit: Oid,
+        branch: String,
+    },
+    /// The editor or an [`interrupt`] canceled the backport. No branches were modified.
+    Canceled,
+    /// The given option, e.g. [`BackportArgs::update_refs`], rewrites history, so it can't be combined with
+    /// [`BackportArgs::cherry_pick`].
+    RequiresRewrite(&'static str),
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Git(error) => Some(error),
+            Error::Editor(error) => Some(error.as_ref()),
+            Error::UnorderedChain { .. }
+            | Error::VerificationFailed { .. }
+            | Error::UnknownStack(_)
+            | Error::InvalidConfig { .. }
+            | Error::Unsplittable(_)
+            | Error::Conflict { .. }
+            | Error::Empty { .. }
+            | Error::Canceled
+            | Error::RequiresRewrite(_) => None,
+        }
+    }
+}
 impl core::fmt::Display for Error {
-    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), fmt::Error> {
-        Ok(())
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Error::Git(error) => write!(f, "Git error: {}", error),
+            Error::UnorderedChain {
+                descendant,
+                ancestor,
+            } => write!(
+                f,
+                "{} is not an ancestor of {}. Branches must be listed from most junior to most senior.",
+                ancestor, descendant
+            ),
+            Error::VerificationFailed { branch, reason } => write!(
+                f,
+                "Verification of {} failed: {}. No branches were modified.",
+                branch, reason
+            ),
+            Error::UnknownStack(name) => write!(f, "No branches configured for stack {}", name),
+            Error::InvalidConfig { key, value } => {
+                write!(f, "Invalid value for {}: {}", key, value)
+            }
+            Error::Editor(error) => write!(f, "Editor error: {}", error),
+            Error::Unsplittable(commit) => {
+                write!(f, "{} is a merge commit and can't be split", commit)
+            }
+            Error::Conflict { from, into } => write!(
+                f,
+                "Applying {} to {} conflicts. No branches were modified.",
+                from, into
+            ),
+            Error::Empty { commit, branch } => write!(
+                f,
+                "{} would become empty on {}. No branches were modified.",
+                commit, branch
+            ),
+            Error::Canceled => write!(f, "Canceled. No branches were modified."),
+            Error::RequiresRewrite(option) => write!(
+                f,
+                "{} rewrites history, so it can't be combined with cherry-picking",
+                option
+            ),
+        }
+    }
+}
+impl From<git2::Error> for Error {
+    fn from(error: git2::Error) -> Self {
+        Error::Git(error)
     }
 }
 
 pub struct BackportCommit<'a> {
     pub commit: Commit<'a>,
-    pub branch_index: RefCell<usize>,
+    /// Index into the backport's branches.
+    pub branch_index: usize,
+    /// Set to combine the commit with an earlier one instead of picking it on its own.
+    pub fold: Option<Fold>,
+    /// Replaces the commit's message, if set.
+    pub message: Option<String>,
+    /// An equivalent commit on a more senior branch, found by patch id.
+    /// Duplicates are dropped wherever their changes are already applied, instead of leaving an empty commit.
+    pub duplicate: Option<Oid>,
+}
+
+impl<'a> BackportCommit<'a> {
+    /// The message the commit will be applied with.
+    pub fn message(&self) -> &str {
+        self.message.as_deref().unwrap_or_else(|| {
+            self.commit
+                .message()
+                .expect("Couldn't get message of commit")
+        })
+    }
+}
+
+/// Combines a commit with an earlier one, like `fixup` and `squash` in `git rebase --interactive`.
+/// The commit is applied right after the one it's folded into, which must be on the same branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fold {
+    pub kind: FoldKind,
+    pub into: Oid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FoldKind {
+    /// Discards the commit's message.
+    Fixup,
+    /// Appends the commit's message to that of the commit it's folded into.
+    Squash,
+}
+
+/// A tip taking part in a backport: Either a local branch or a detached commit-ish.
+pub struct Tip<'a> {
+    /// Display name, i.e. the branch's shorthand or the revspec the tip was resolved from.
+    pub name: String,
+    pub commit: Commit<'a>,
+    /// The local branch to point at the rewritten tip, if any.
+    /// It's created if it doesn't exist yet.
+    pub branch: Option<String>,
+}
+
+impl<'a> Tip<'a> {
+    pub fn from_branch(branch: &Branch<'a>) -> Result<Self, git2::Error> {
+        let name = branch
+            .name()?
+            .ok_or_else(|| git2::Error::from_str("Branch name is not valid UTF-8"))?
+            .to_string();
+        Ok(Self {
+            commit: branch.get().peel_to_commit()?,
+            branch: Some(name.clone()),
+            name,
+        })
+    }
+
+    /// Resolves a revspec (`HEAD`, a branch, tag or any commit-ish) as tip.
+    /// Local branches (including `HEAD` if it's attached) are updated by the backport, anything else is detached.
+    pub fn resolve_head(repository: &'a Repository, spec: &str) -> Result<Self, git2::Error> {
+        let (object, reference) = repository.revparse_ext(spec)?;
+        let reference = match reference {
+            Some(reference) if reference.is_branch() => Some(reference),
+            Some(reference) if reference.kind() == Some(git2::ReferenceType::Symbolic) => {
+                Some(reference.resolve()?).filter(|r| r.is_branch())
+            }
+            _ => None,
+        };
+        match reference {
+            Some(reference) => Self::from_branch(&Branch::wrap(reference)),
+            None => Ok(Self {
+                name: spec.to_string(),
+                commit: object.peel_to_commit()?,
+                branch: None,
+            }),
+        }
+    }
+
+    /// Resolves a revspec as detached tip, even if it names a local branch, so that it's never updated.
+    pub fn resolve_detached(repository: &'a Repository, spec: &str) -> Result<Self, git2::Error> {
+        Ok(Self {
+            name: spec.to_string(),
+            commit: repository.revparse_single(spec)?.peel_to_commit()?,
+            branch: None,
+        })
+    }
+
+    /// Resolves a local branch given either by its shorthand (`release/1.x`) or full refname (`refs/heads/release/1.x`).
+    pub fn resolve_branch(repository: &'a Repository, name: &str) -> Result<Self, git2::Error> {
+        if name.starts_with("refs/") {
+            let reference = repository.find_reference(name)?;
+            if !reference.is_branch() {
+                return Err(git2::Error::from_str(&format!(
+                    "{} is not a local branch",
+                    name
+                )));
+            }
+            Self::from_branch(&Branch::wrap(reference))
+        } else {
+            Self::from_branch(&repository.find_branch(name, BranchType::Local)?)
+        }
+    }
+}
+
+/// Finds local branches whose tips are proper ancestors of `head`, ordered from most junior to most senior.
+///
+/// If `patterns` is not empty, only branches matching at least one of these globs (e.g. `release/*`) are considered.
+pub fn discover_ancestors<'a>(
+    repository: &'a Repository,
+    head: &Tip<'a>,
+    patterns: &[String],
+) -> Result<Vec<Tip<'a>>, Error> {
+    let mut candidates = HashMap::new();
+    let mut consider = |branch: Branch<'a>| -> Result<(), Error> {
+        let tip = Tip::from_branch(&branch)?;
+        if tip.branch != head.branch
+            && tip.commit.id() != head.commit.id()
+            && repository.graph_descendant_of(head.commit.id(), tip.commit.id())?
+        {
+            trace!("Found ancestor branch {}.", tip.name);
+            candidates.insert(tip.name.clone(), tip);
+        }
+        Ok(())
+    };
+    if patterns.is_empty() {
+        for branch in repository.branches(Some(BranchType::Local))? {
+            consider(branch?.0)?
+        }
+    } else {
+        for pattern in patterns {
+            for reference in repository.references_glob(&format!("refs/heads/{}", pattern))? {
+                consider(Branch::wrap(reference?))?
+            }
+        }
+    }
+
+    // Fewer commits between the head and a tip means a more junior branch.
+    let mut ancestors = candidates
+        .into_values()
+        .map(|tip| {
+            let (ahead, _) = repository.graph_ahead_behind(head.commit.id(), tip.commit.id())?;
+            Ok((ahead, tip))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    ancestors.sort_by(|(a_ahead, a), (b_ahead, b)| {
+        a_ahead.cmp(b_ahead).then_with(|| a.name.cmp(&b.name))
+    });
+    let ancestors = ancestors
+        .into_iter()
+        .map(|(_, tip)| tip)
+        .collect::<Vec<_>>();
+
+    validate_chain(repository, &ancestors)?;
+    Ok(ancestors)
+}
+
+/// Checks that each tip in `branches` is an ancestor of (or identical to) the one before it.
+pub fn validate_chain(repository: &Repository, branches: &[Tip]) -> Result<(), Error> {
+    for window in branches.windows(2) {
+        let (descendant, ancestor) = match window {
+            [descendant, ancestor] => (descendant, ancestor),
+            _ => unreachable!(),
+        };
+        if descendant.commit.id() != ancestor.commit.id()
+            && !repository.graph_descendant_of(descendant.commit.id(), ancestor.commit.id())?
+        {
+            return Err(Error::UnorderedChain {
+                descendant: descendant.name.clone(),
+                ancestor: ancestor.name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// How overlapping changes are resolved while cherrypicking and merging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Conflicts abort the backport.
+    #[default]
+    Normal,
+    Ours,
+    Theirs,
+    Union,
+}
+impl FromStr for MergeStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "normal" => MergeStrategy::Normal,
+            "ours" => MergeStrategy::Ours,
+            "theirs" => MergeStrategy::Theirs,
+            "union" => MergeStrategy::Union,
+            _ => return Err(format!("Unknown merge strategy: {}", s)),
+        })
+    }
+}
+impl MergeStrategy {
+    fn merge_options(self) -> MergeOptions {
+        let mut options = MergeOptions::new();
+        options
+            .find_renames(true)
+            // Conflicts are reported through the index instead, see `write_tree`.
+            .fail_on_conflict(false)
+            .minimal(true)
+            .file_favor(match self {
+                MergeStrategy::Normal => FileFavor::Normal,
+                MergeStrategy::Ours => FileFavor::Ours,
+                MergeStrategy::Theirs => FileFavor::Theirs,
+                MergeStrategy::Union => FileFavor::Union,
+            });
+        options
+    }
+}
+
+/// Writes the tree of `index`, the result of applying `from` (a commit or branch) to the branch `into`,
+/// or fails with [`Error::Conflict`] if that conflicted.
+fn write_tree(
+    repository: &Repository,
+    index: &mut Index,
+    from: impl ToString,
+    into: &str,
+) -> Result<Oid, Error> {
+    if index.has_conflicts() {
+        return Err(Error::Conflict {
+            from: from.to_string(),
+            into: into.to_string(),
+        });
+    }
+    Ok(index.write_tree_to(repository)?)
+}
+
+/// Cherry-picks `commit` onto `onto`, relative to `parent_id` if it's a merge, then folds in `folded`.
+/// Returns the resulting tree, or fails with [`Error::Conflict`] naming `id` or the folded commit and `into`.
+#[allow(clippy::too_many_arguments)]
+fn pick_tree(
+    repository: &Repository,
+    commit: &Commit,
+    id: Oid,
+    parent_id: Oid,
+    folded: &[&BackportCommit],
+    onto: &Commit,
+    merge_strategy: MergeStrategy,
+    into: &str,
+) -> Result<Oid, Error> {
+    // 1-based, with 0 meaning "not a merge".
+    let mainline = if commit.parent_count() > 1 {
+        commit.parent_ids().position(|p| p == parent_id).unwrap() + 1
+    } else {
+        0
+    };
+    let options = merge_strategy.merge_options();
+    let mut index = repository.cherrypick_commit(commit, onto, mainline as u32, Some(&options))?;
+    let mut tree = write_tree(repository, &mut index, id, into)?;
+    for folded in folded {
+        info!("Folding in {}...", folded.commit.id());
+        let mut index = repository.merge_trees(
+            &folded.commit.parent(0)?.tree()?,
+            &repository.find_tree(tree)?,
+            &folded.commit.tree()?,
+            Some(&options),
+        )?;
+        tree = write_tree(repository, &mut index, folded.commit.id(), into)?;
+    }
+    Ok(tree)
+}
+
+/// Message templates for commits created by the backport.
+#[derive(Debug, Clone)]
+pub struct Messages {
+    /// Used when catching up a branch. `{from}` and `{into}` are replaced with the respective branch names.
+    pub merge: String,
+    /// Used for cherrypicked commits. `{message}` is replaced with the original message and `{id}` with the original commit id.
+    pub commit: String,
+}
+impl Default for Messages {
+    fn default() -> Self {
+        Self {
+            merge: "Merge {from} into {into}".to_string(),
+            commit: "{message}".to_string(),
+        }
+    }
+}
+
+/// Appends the messages of squashed commits, without their `squash! ` subjects.
+fn squash_message(message: &str, folded: &[&BackportCommit]) -> String {
+    let mut message = message.to_string();
+    for commit in folded {
+        if commit.fold.map(|fold| fold.kind) != Some(FoldKind::Squash) {
+            continue;
+        }
+        let folded_message = commit.message();
+        let folded_message = if folded_message.starts_with("squash! ") {
+            folded_message.split_once('\n').map_or("", |(_, body)| body)
+        } else {
+            folded_message
+        };
+        if !folded_message.trim().is_empty() {
+            message = format!("{}\n\n{}\n", message.trim_end(), folded_message.trim());
+        }
+    }
+    message
+}
+
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |message, (key, value)| {
+            message.replace(&format!("{{{}}}", key), value)
+        })
+}
+
+/// What happens to commits that become empty because their changes are already applied, like `--empty` of
+/// `git rebase`. Commits that were empty to begin with are always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyCommits {
+    #[default]
+    Drop,
+    Keep,
+    /// Fails with [`Error::Empty`] without modifying any branches.
+    Stop,
+}
+impl FromStr for EmptyCommits {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "drop" => EmptyCommits::Drop,
+            "keep" => EmptyCommits::Keep,
+            "stop" => EmptyCommits::Stop,
+            _ => return Err(format!("Unknown empty commit handling: {}", s)),
+        })
+    }
+}
+
+/// Whether to drop `commit`, applied as `id` with the resulting `tree` onto `parent` on `branch`.
+/// Duplicates are dropped regardless of `empty`.
+fn drop_empty(
+    commit: &BackportCommit,
+    id: Oid,
+    tree: Oid,
+    parent: &Commit,
+    empty: EmptyCommits,
+    branch: &str,
+) -> Result<bool, Error> {
+    if tree != parent.tree_id()
+        || commit.commit.parent_count() != 1
+        || commit.commit.tree_id() == commit.commit.parent(0)?.tree_id()
+    {
+        return Ok(false);
+    }
+    if let Some(duplicate) = commit.duplicate {
+        info!(
+            "Dropping {}, since its duplicate {} is already on {}.",
+            id, duplicate, branch
+        );
+        return Ok(true);
+    }
+    match empty {
+        EmptyCommits::Drop => {
+            info!("Dropping {}, which became empty on {}.", id, branch);
+            Ok(true)
+        }
+        EmptyCommits::Keep => Ok(false),
+        EmptyCommits::Stop => Err(Error::Empty {
+            commit: id,
+            branch: branch.to_string(),
+        }),
+    }
+}
+
+/// Which path to follow when the next branch is reachable through several parents of a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParentPreference {
+    #[default]
+    FirstParent,
+    /// The parent with the fewest commits between it and the next branch's tip.
+    /// Ties are resolved towards earlier parents.
+    FewestCommits,
+}
+impl FromStr for ParentPreference {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "first-parent" => ParentPreference::FirstParent,
+            "fewest-commits" => ParentPreference::FewestCommits,
+            _ => return Err(format!("Unknown parent preference: {}", s)),
+        })
+    }
+}
+
+/// A parent of a merge commit through which the next more senior branch is reachable.
+pub struct ParentCandidate<'a> {
+    /// The parent's position in the merge commit.
+    pub index: usize,
+    pub commit: Commit<'a>,
+    /// Number of commits between this parent and the next branch's tip.
+    pub distance: usize,
 }
 
-pub struct BackportArgs<'a, E: FnOnce(&[Branch], &[BackportCommit])> {
+pub struct BackportArgs<'a, E: Editor> {
     pub repository: &'a Repository,
     pub backup: bool,
-    pub branches: &'a [Branch<'a>],
-    pub edit: E,
+    /// The head followed by its ancestors, from most junior to most senior.
+    pub branches: &'a [Tip<'a>],
+    /// Further branches stacked on `branches`, e.g. other features based on the same `develop`.
+    /// Each is rebased onto the rewritten version of the most junior of `branches` it contains, or merged with it when
+    /// cherry-picking. Siblings stacked on other siblings are based on those instead. Their own commits aren't part of
+    /// the plan.
+    pub siblings: &'a [Tip<'a>],
+    pub merge_strategy: MergeStrategy,
+    pub messages: Messages,
+    pub parent_preference: ParentPreference,
+    /// Checks the rewritten tips before updating any branches:
+    /// Each must descend from the next more senior one, and branches that neither gained nor lost commits must keep their tree.
+    pub verify: bool,
+    /// Folds commits with `fixup! ` and `squash! ` subjects into the earlier commit they name, see [`Plan::autosquash`].
+    pub autosquash: bool,
+    /// Leaves existing commits untouched: Commits moved to more senior branches are cherry-picked onto their tips instead,
+    /// which are then merged forward into the more junior branches. Plans that would rewrite history are rejected,
+    /// as are `update_refs` and `update_tags`.
+    pub cherry_pick: bool,
+    pub empty: EmptyCommits,
+    /// Also rebases other local branches containing rewritten commits, like `siblings`, instead of only reporting them.
+    /// Branches pointing at a rewritten commit are moved to its new version.
+    pub update_refs: bool,
+    /// Moves tags pointing at rewritten commits to their new versions, instead of only reporting them.
+    /// Annotated tags are recreated with the same message and tagger.
+    pub update_tags: bool,
+    pub editor: E,
 }
+
+/// Returns the rewritten tips in the order of `branches`, followed by those of `siblings`,
+/// or [`Error::Canceled`] without modifying anything if the editor cancels or it's interrupted before updating branches.
 #[allow(clippy::cognitive_complexity)]
-pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
+pub fn backport<E: Editor>(
     BackportArgs {
         repository,
         backup,
         branches,
-        edit,
+        siblings,
+        merge_strategy,
+        messages,
+        parent_preference,
+        verify,
+        autosquash,
+        cherry_pick,
+        empty,
+        update_refs,
+        update_tags,
+        mut editor,
     }: BackportArgs<E>,
-) -> Result<(), Error> {
-    info!("Collecting commits...");
+) -> Result<Vec<Oid>, Error> {
     assert!(!branches.is_empty());
+    let _run = interrupt::start();
+    if cherry_pick && update_refs {
+        return Err(Error::RequiresRewrite("Updating refs"));
+    }
+    if cherry_pick && update_tags {
+        return Err(Error::RequiresRewrite("Updating tags"));
+    }
+    validate_chain(repository, branches)?;
+
+    // Nothing below the most senior tip is collected or rewritten.
+    let mut graph = CommitGraph::with_floor(repository, branches.last().unwrap().commit.id());
+
+    let siblings = stack_siblings(&mut graph, branches, &siblings.iter().collect::<Vec<_>>())?;
+
+    info!("Collecting commits...");
     let mut commits = vec![];
     'branch: for (current_index, window) in branches.windows(2).enumerate() {
         let (current, parent) = if let [current, parent] = window {
@@ -51,17 +613,13 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
         } else {
             unreachable!()
         };
-        let mut current_commit = current.get().peel_to_commit().unwrap();
-        let parent_branch_id = parent.get().peel_to_commit().unwrap().id();
+        let mut current_commit = current.commit.clone();
+        let parent_branch_id = parent.commit.id();
         loop {
             if current_commit.id() == parent_branch_id {
                 continue 'branch;
             }
-            trace!(
-                "Found commit: {} on {}",
-                current_commit.id(),
-                current.name().unwrap().unwrap(),
-            );
+            trace!("Found commit: {} on {}", current_commit.id(), current.name);
             let parent_commit = if current_commit.parent_count() == 1 {
                 current_commit.parent(0).unwrap()
             } else {
@@ -69,53 +627,116 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
                     "Found {} parents. Scanning...",
                     current_commit.parent_count()
                 );
-                let mut visited = HashSet::new();
-                let matching_parents = current_commit
-                    .parents()
-                    .rev() // The commit we're looking for tends to be on the merged-in branch, at least with my workflow. -TS
-                    .filter(|p| {
-                        fn is_or_has_ancestor(
-                            c: &Commit,
-                            id: Oid,
-                            visited: &mut HashSet<Oid>,
-                        ) -> bool {
-                            visited.insert(c.id())
-                                && (c.id() == id
-                                    || c.parents()
-                                        .rev()
-                                        .any(|p| is_or_has_ancestor(&p, id, visited)))
-                        };
-                        is_or_has_ancestor(p, parent_branch_id, &mut visited)
-                    })
-                    .collect::<Vec<_>>();
-                assert_eq!(
-                    matching_parents.len(),
-                    1,
-                    "Ambiguous parents found. The next ancestor must be reachable via only one parent in each commit."
+                let mut matching_parents = vec![];
+                for (index, p) in current_commit.parents().enumerate() {
+                    if graph.is_or_has_ancestor(p.id(), parent_branch_id)? {
+                        matching_parents.push(ParentCandidate {
+                            index,
+                            distance: repository.graph_ahead_behind(p.id(), parent_branch_id)?.0,
+                            commit: p,
+                        })
+                    }
+                }
+                assert!(
+                    !matching_parents.is_empty(),
+                    "{} is not reachable from {}",
+                    parent.name,
+                    current_commit.id()
                 );
-                matching_parents.into_iter().next().unwrap()
+                let chosen = if matching_parents.len() == 1 {
+                    0
+                } else {
+                    let chosen = editor
+                        .choose_parent(&current_commit, &matching_parents)
+                        .map_err(Error::Editor)?
+                        .unwrap_or_else(|| match parent_preference {
+                            ParentPreference::FirstParent => 0,
+                            ParentPreference::FewestCommits => {
+                                matching_parents
+                                    .iter()
+                                    .enumerate()
+                                    .min_by_key(|(_, candidate)| candidate.distance)
+                                    .unwrap()
+                                    .0
+                            }
+                        });
+                    warn!(
+                        "Ambiguous ancestry: {} reaches {} through parents {}. Following parent {} ({}).",
+                        current_commit.id(),
+                        parent.name,
+                        matching_parents
+                            .iter()
+                            .map(|candidate| format!("{} ({} commits)", candidate.index + 1, candidate.distance))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        matching_parents[chosen].index + 1,
+                        matching_parents[chosen].commit.id(),
+                    );
+                    chosen
+                };
+                matching_parents.swap_remove(chosen).commit
             };
             commits.push(BackportCommit {
                 commit: current_commit,
-                branch_index: RefCell::new(current_index),
+                branch_index: current_index,
+                fold: None,
+                message: None,
+                duplicate: None,
             });
             current_commit = parent_commit;
         }
     }
 
-    edit(&branches, &commits);
+    let original_branch_indices = commits
+        .iter()
+        .map(|commit| (commit.commit.id(), commit.branch_index))
+        .collect::<HashMap<_, _>>();
+
+    info!("Detecting duplicates...");
+    patch_id::mark_duplicates(repository, branches, &mut commits)?;
+
+    // `Backport-To: <branch>` trailers move commits to the most senior branch they name, but never to a more junior one.
+    for commit in &mut commits {
+        for (key, value) in rules::trailers(commit.commit.message().unwrap_or_default()) {
+            if !key.eq_ignore_ascii_case("Backport-To") {
+                continue;
+            }
+            match branches.iter().position(|branch| branch.name == value) {
+                Some(branch_index) => {
+                    trace!(
+                        "Found trailer: {}: {} on {}",
+                        key,
+                        value,
+                        commit.commit.id()
+                    );
+                    commit.branch_index = commit.branch_index.max(branch_index);
+                }
+                None => warn!(
+                    "{} has a {} trailer for {}, which isn't part of the backport. Ignoring it.",
+                    commit.commit.id(),
+                    key,
+                    value
+                ),
+            }
+        }
+    }
+
+    // Commits at or below the most senior tip's generation can't reach anything that's rewritten.
+    let floor = graph.generation(branches.last().unwrap().commit.id())?;
 
     info!("Detecting forks...");
-    let forks = {
-        let mut visited = HashSet::new();
-        let mut forks = HashMap::new();
+    let fork_points = {
+        let mut chain = HashMap::new();
+        let mut disregarded = HashSet::new();
+        let mut fork_points = vec![vec![]; commits.len()];
 
-        for current_parent in commits
+        for (position, current_parent) in commits
             .iter()
             .map(Some)
             .chain([None].iter().copied())
             .collect::<Vec<_>>()
             .windows(2)
+            .enumerate()
             .rev()
         {
             let (current, parents) = match current_parent {
@@ -131,73 +752,117 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
                 ),
                 _ => unreachable!(),
             };
-            visited.insert(current.commit.id());
-            trace!(
-                " Checking parents of {} on branch {1}...",
-                current.commit.id(),
-                *current.branch_index.borrow()
-            );
+            chain.insert(current.commit.id(), position);
+            trace!(" Checking parents of {}...", current.commit.id());
             for parent in parents {
-                visit(
-                    parent,
-                    &mut visited,
-                    *current.branch_index.borrow(),
-                    &mut forks,
-                );
-                fn visit(
-                    commit: Commit,
-                    visited: &mut HashSet<Oid>,
-                    branch_index: usize,
-                    forks: &mut HashMap<Oid, usize>,
-                ) -> bool {
-                    if !visited.contains(&commit.id()) {
-                        //trace!("  Found side chain commit {}.", commit.id());
-                        let mut found_fork = false;
-                        for parent in commit.parents() {
-                            found_fork |= visit(parent, visited, branch_index, forks)
-                        }
-                        if !found_fork {
-                            // This commit can safely be disregarded in the future.
-                            visited.insert(commit.id());
+                // Side chains are walked only down to the chain, commits known to be fork-free and the most senior tip,
+                // below which no chain commits can be reached.
+                let (side_chain, stopped) = graph.walk(parent.id(), floor, |id| {
+                    chain.contains_key(&id) || disregarded.contains(&id)
+                })?;
+                let mut found_fork = false;
+                for id in stopped {
+                    if let Some(&fork) = chain.get(&id) {
+                        trace!("  Found fork commit {}.", id);
+                        if !fork_points[position].contains(&fork) {
+                            fork_points[position].push(fork);
                         }
-                        found_fork
-                    } else {
-                        trace!("  Found fork commit {}.", commit.id());
-                        // Fork found.
-                        // Only the ones that are actually on the edited chain are interesting here, but the overhead shouldn't be too bad.
-                        // Larger branch_index equals a more senior branch, which is necessary here to make sure changes stay where they should.
-                        if let Some(old_value) = forks.insert(commit.id(), branch_index) {
-                            if old_value > branch_index {
-                                *forks.get_mut(&commit.id()).unwrap() = old_value
-                            }
-                        }
-                        true
+                        found_fork = true;
                     }
                 }
+                if !found_fork {
+                    // These commits can safely be disregarded in the future.
+                    disregarded.extend(side_chain);
+                }
             }
         }
-        forks
+        fork_points
     };
 
-    if backup {
-        for branch in branches {
-            let backup_name = "git-backport-backup/".to_string() + branch.name().unwrap().unwrap();
-            let mut i = 0usize;
-            while {
-                let backup_name = if i == 0 {
-                    Cow::Borrowed(&backup_name)
-                } else {
-                    Cow::Owned(backup_name.clone() + "-" + &i.to_string())
-                };
-                repository
-                    .branch(
-                        backup_name.as_ref(),
-                        &branch.get().peel_to_commit().unwrap(),
-                        false,
-                    )
-                    .is_err()
-            } {
-                i += 1
+    let mut plan = Plan {
+        repository,
+        branches,
+        merge_strategy,
+        commits,
+        forks: fork_points,
+        origins: HashMap::new(),
+        replaces: HashMap::new(),
+    };
+    if autosquash {
+        plan.autosquash();
+    }
+    let mut problems = vec![];
+    loop {
+        match editor.edit(&mut plan, &problems).map_err(Error::Editor)? {
+            EditOutcome::Apply => (),
+            EditOutcome::Cancel => return Err(Error::Canceled),
+        }
+        problems = plan.validate();
+        if cherry_pick {
+            let original_branch_indices = (0..plan.commits.len())
+                .map(|position| original_branch_indices[&plan.origin(position)])
+                .collect::<Vec<_>>();
+            problems.extend(cherry_pick::problems(&plan, &original_branch_indices));
+        }
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+        if problems.is_empty() && editor.confirm(&plan).map_err(Error::Editor)? {
+            break;
+        }
+    }
+    // Split commits' parts count as found where the commit was.
+    let original_branch_indices = (0..plan.commits.len())
+        .map(|position| original_branch_indices[&plan.origin(position)])
+        .collect::<Vec<_>>();
+
+    if cherry_pick {
+        let heads =
+            cherry_pick::apply(&plan, &siblings, &original_branch_indices, &messages, empty)?;
+        return set_branches(
+            repository,
+            branches,
+            &siblings,
+            &plan.commits,
+            &original_branch_indices,
+            heads,
+            verify,
+            backup,
+            &mut graph,
+        );
+    }
+
+    let Plan {
+        commits,
+        forks: fork_points,
+        origins,
+        replaces,
+        ..
+    } = plan;
+    // Parts of split commits aren't part of history, except for the newest ones, which stand in for their commits.
+    let history_id = |commit: &BackportCommit| {
+        let id = commit.commit.id();
+        replaces.get(&id).copied().unwrap_or(id)
+    };
+
+    // Commits folded into each commit, oldest first.
+    let mut folds = HashMap::<_, Vec<_>>::new();
+    for commit in commits.iter().rev() {
+        if let Some(fold) = commit.fold {
+            folds.entry(fold.into).or_default().push(commit);
+        }
+    }
+
+    // Fork commits are caught up to the most senior branch merging any side chain forked from them,
+    // which is necessary here to make sure changes stay where they should.
+    let mut forks = HashMap::new();
+    for (commit, fork_points) in commits.iter().zip(fork_points.iter()) {
+        for &fork in fork_points {
+            let old_value = forks
+                .entry(commits[fork].commit.id())
+                .or_insert(commit.branch_index);
+            if *old_value < commit.branch_index {
+                *old_value = commit.branch_index
             }
         }
     }
@@ -210,32 +875,29 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
 
     info!("Transforming history...");
 
-    #[allow(clippy::for_loop_over_option)]
-    for BackportCommit {
-        commit: oldest,
-        branch_index,
-    } in commits.last()
-    {
-        // Always unchanged.
-        map.insert(oldest.id(), oldest.clone());
-        inverse_map.insert(oldest.id(), oldest.clone());
-        heads[*branch_index.borrow()] = Some(oldest.clone());
-        for dirty in dirty[0..*branch_index.borrow()].iter_mut() {
-            *dirty = true;
-        }
+    // The most senior branch is the base of everything else and always unchanged.
+    let senior = branches.last().unwrap().commit.clone();
+    map.insert(senior.id(), senior.clone());
+    inverse_map.insert(senior.id(), senior.clone());
+    heads[branches.len() - 1] = Some(senior.clone());
+    for dirty in dirty[0..branches.len() - 1].iter_mut() {
+        *dirty = true;
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn catch_up_branch<'a>(
         branch_index: usize,
-        branches: &[Branch],
+        branches: &[Tip],
         heads: &mut [Option<Commit<'a>>],
         inverse_map: &mut HashMap<Oid, Commit<'a>>,
         branch_map_overlays: &mut [HashMap<Oid, Commit<'a>>],
         dirty: &mut [bool],
         repository: &'a Repository,
-    ) -> Oid {
+        merge_strategy: MergeStrategy,
+        merge_message: &str,
+    ) -> Result<Oid, Error> {
         if branch_index == branches.len() - 1 || !dirty[branch_index] {
-            return inverse_map[&heads[branch_index].as_ref().unwrap().id()].id();
+            return Ok(inverse_map[&heads[branch_index].as_ref().unwrap().id()].id());
         }
         let original_commit_id = catch_up_branch(
             branch_index + 1,
@@ -245,26 +907,24 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
             branch_map_overlays,
             dirty,
             repository,
-        );
+            merge_strategy,
+            merge_message,
+        )?;
         trace!("Catching up branch {}...", branch_index);
-        heads[branch_index] = Some(match heads[branch_index].as_ref() {
-            None => heads[branch_index + 1].as_ref().unwrap().clone(),
+        let (head, created) = match heads[branch_index].as_ref() {
+            None => (heads[branch_index + 1].as_ref().unwrap().clone(), false),
             Some(head) => {
-                let mut merge_index = repository
-                    .merge_commits(
-                        head,
-                        heads[branch_index + 1].as_ref().unwrap(),
-                        Some(
-                            MergeOptions::new()
-                                .find_renames(true)
-                                .fail_on_conflict(true)
-                                .minimal(true),
-                        ),
-                    )
-                    .expect(
-                        "This should never fail, since the changes were compatible to begin with.",
-                    );
-                let merge_oid = merge_index.write_tree_to(repository).unwrap();
+                let mut merge_index = repository.merge_commits(
+                    head,
+                    heads[branch_index + 1].as_ref().unwrap(),
+                    Some(&merge_strategy.merge_options()),
+                )?;
+                let merge_oid = write_tree(
+                    repository,
+                    &mut merge_index,
+                    &branches[branch_index + 1].name,
+                    &branches[branch_index].name,
+                )?;
                 let merge_tree = repository.find_tree(merge_oid).unwrap();
                 let signature = repository
                     .signature()
@@ -274,143 +934,186 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
                         None,
                         &signature,
                         &signature,
-                        &format!(
-                            "Merge {} into {}",
-                            branches[branch_index + 1].name().unwrap().unwrap(),
-                            branches[branch_index].name().unwrap().unwrap(),
+                        &fill_template(
+                            merge_message,
+                            &[
+                                ("from", &branches[branch_index + 1].name),
+                                ("into", &branches[branch_index].name),
+                            ],
                         ),
                         &merge_tree,
                         &[head, heads[branch_index + 1].as_ref().unwrap()],
                     )
                     .unwrap();
-                repository.find_commit(merge_commit_id).unwrap()
+                (repository.find_commit(merge_commit_id).unwrap(), true)
             }
-        });
+        };
         assert!(branch_map_overlays[branch_index]
-            .insert(
-                original_commit_id,
-                heads[branch_index].as_ref().unwrap().clone()
-            )
-            .is_none());
-        assert!(inverse_map
-            .insert(
-                heads[branch_index].as_ref().unwrap().id(),
-                repository.find_commit(original_commit_id).unwrap()
-            )
+            .insert(original_commit_id, head.clone())
             .is_none());
+        if created {
+            assert!(inverse_map
+                .insert(
+                    head.id(),
+                    repository.find_commit(original_commit_id).unwrap()
+                )
+                .is_none());
+        }
+        heads[branch_index] = Some(head);
         dirty[branch_index] = false;
-        original_commit_id
+        Ok(original_commit_id)
     }
 
-    for commit_parent in commits.windows(2).rev() {
-        let (commit, BackportCommit { commit: parent, .. }) = match commit_parent {
-            [commit, parent] => (commit, parent),
+    for commit_parent in commits
+        .iter()
+        .map(Some)
+        .chain([None].iter().copied())
+        .collect::<Vec<_>>()
+        .windows(2)
+        .rev()
+    {
+        let (commit, parent_id) = match commit_parent {
+            [Some(commit), Some(parent)] => (*commit, history_id(parent)),
+            [Some(commit), None] => (*commit, senior.id()),
             _ => unreachable!(),
         };
+        let branch_index = commit.branch_index;
+        let fork_target_branch_index = forks.get(&commit.commit.id()).copied();
+        if commit.fold.is_some() {
+            // Already applied along with the commit it's folded into, which is on the same branch.
+            trace!("Folded {}.", commit.commit.id());
+            let head = heads[branch_index].as_ref().unwrap().clone();
+            assert!(map.insert(history_id(commit), head).is_none());
+            if let Some(fork_target_branch_index) = fork_target_branch_index {
+                catch_up_branch(
+                    fork_target_branch_index,
+                    branches,
+                    heads.as_mut_slice(),
+                    &mut inverse_map,
+                    branch_map_overlays.as_mut_slice(),
+                    dirty.as_mut_slice(),
+                    repository,
+                    merge_strategy,
+                    &messages.merge,
+                )?;
+            }
+            continue;
+        }
+        let folded = folds
+            .get(&commit.commit.id())
+            .map_or(&[][..], Vec::as_slice);
         catch_up_branch(
-            *commit.branch_index.borrow(),
+            branch_index,
             branches,
             heads.as_mut_slice(),
             &mut inverse_map,
             branch_map_overlays.as_mut_slice(),
             dirty.as_mut_slice(),
             repository,
-        );
-
-        let mainline = commit
-            .commit
-            .parents()
-            .enumerate()
-            .find_map(|(i, p)| if p.id() == parent.id() { Some(i) } else { None })
-            .unwrap();
-
-        info!("Cherrypicking {}...", commit.commit.id());
-        let mut cherrypick_index = repository
-            .cherrypick_commit(
-                &commit.commit,
-                heads[*commit.branch_index.borrow()].as_ref().unwrap(),
-                mainline as u32,
-                Some(
-                    MergeOptions::new()
-                        .find_renames(true)
-                        .fail_on_conflict(true)
-                        .minimal(true),
-                ),
-            )
-            .expect("Failed to cherrypick");
+            merge_strategy,
+            &messages.merge,
+        )?;
+        let head = heads[branch_index].as_ref().unwrap().clone();
 
         let cherrypick_parents = commit
             .commit
             .parents()
             .map(|p| {
-                if p.id() == parent.id() {
-                    heads[*commit.branch_index.borrow()]
-                        .as_ref()
-                        .unwrap()
-                        .clone()
+                if p.id() == parent_id {
+                    Ok(head.clone())
                 } else {
-                    map_commit(p, &mut map, &mut inverse_map)
+                    map_commit(
+                        p,
+                        &branch_map_overlays[branch_index],
+                        &branches[branch_index].name,
+                        &mut map,
+                        &mut inverse_map,
+                        &mut graph,
+                        floor,
+                        repository,
+                        merge_strategy,
+                    )
                 }
             })
-            .collect::<Vec<_>>();
-
-        fn map_commit<'a>(
-            commit: Commit<'a>,
-            map: &mut HashMap<Oid, Commit<'a>>,
-            inverse_map: &mut HashMap<Oid, Commit<'a>>,
-        ) -> Commit<'a> {
-            if let Some(mapped) = map.get(&commit.id()) {
-                return mapped.clone();
-            }
+            .collect::<Result<Vec<_>, Error>>()?;
 
-            let parents = commit.parents().collect::<Vec<_>>();
-            let mapped_parents = parents
-                .iter()
-                .cloned()
-                .map(|p| map_commit(p, map, inverse_map))
-                .collect::<Vec<_>>();
-            if parents
-                .iter()
-                .zip(mapped_parents.iter())
-                .all(|(a, b)| a.id() == b.id())
-            {
-                map.insert(commit.id(), commit.clone());
-                inverse_map.insert(commit.id(), commit.clone());
-                return commit;
-            }
-            todo!();
-        }
-
-        let cherrypick_tree = cherrypick_index.write_tree_to(repository).unwrap();
-        let cherrypick_tree = repository.find_tree(cherrypick_tree).unwrap();
+        let unchanged = head.id() == parent_id
+            && folded.is_empty()
+            && commit.message.is_none()
+            && commit
+                .commit
+                .parent_ids()
+                .zip(cherrypick_parents.iter())
+                .all(|(a, b)| a == b.id());
+        // `None` if the commit is dropped.
+        let cherrypick_commit = if unchanged {
+            trace!("Keeping {}.", commit.commit.id());
+            Some(commit.commit.clone())
+        } else {
+            let id = origins
+                .get(&commit.commit.id())
+                .copied()
+                .unwrap_or_else(|| commit.commit.id());
+            info!("Cherrypicking {}...", commit.commit.id());
+            let cherrypick_tree = pick_tree(
+                repository,
+                &commit.commit,
+                id,
+                parent_id,
+                folded,
+                &head,
+                merge_strategy,
+                &branches[branch_index].name,
+            )?;
+            let cherrypick_tree = repository.find_tree(cherrypick_tree).unwrap();
 
-        let cherrypick_commit = repository
-            .commit(
-                None,
-                &commit.commit.author(),
-                &repository.signature().unwrap(),
-                commit
-                    .commit
-                    .message()
-                    .expect("Couldn't get message of commit"),
-                &cherrypick_tree,
-                cherrypick_parents.iter().collect::<Vec<_>>().as_slice(),
-            )
-            .unwrap();
-        let cherrypick_commit = repository.find_commit(cherrypick_commit).unwrap();
-        assert!(map
-            .insert(commit.commit.id(), cherrypick_commit.clone())
-            .is_none());
-        assert!(inverse_map
-            .insert(cherrypick_commit.id(), commit.commit.clone())
-            .is_none());
-        heads[*commit.branch_index.borrow()] = Some(cherrypick_commit);
+            if drop_empty(
+                commit,
+                commit.commit.id(),
+                cherrypick_tree.id(),
+                &head,
+                empty,
+                &branches[branch_index].name,
+            )? {
+                None
+            } else {
+                let cherrypick_commit = repository
+                    .commit(
+                        None,
+                        &commit.commit.author(),
+                        &repository.signature().unwrap(),
+                        &fill_template(
+                            &messages.commit,
+                            &[
+                                ("message", &squash_message(commit.message(), folded)),
+                                ("id", &id.to_string()),
+                            ],
+                        ),
+                        &cherrypick_tree,
+                        cherrypick_parents.iter().collect::<Vec<_>>().as_slice(),
+                    )
+                    .unwrap();
+                Some(repository.find_commit(cherrypick_commit).unwrap())
+            }
+        };
+        let history_id = history_id(commit);
+        match cherrypick_commit {
+            Some(cherrypick_commit) => {
+                assert!(map.insert(history_id, cherrypick_commit.clone()).is_none());
+                assert!(inverse_map
+                    .insert(cherrypick_commit.id(), repository.find_commit(history_id)?)
+                    .is_none());
+                heads[branch_index] = Some(cherrypick_commit);
 
-        for dirty in dirty[0..*commit.branch_index.borrow()].iter_mut() {
-            *dirty = true;
+                for dirty in dirty[0..branch_index].iter_mut() {
+                    *dirty = true;
+                }
+            }
+            // Like folded commits, dropped ones stand for the branch they would have been applied to.
+            None => assert!(map.insert(history_id, head).is_none()),
         }
 
-        if let Some(&fork_target_branch_index) = forks.get(&commit.commit.id()) {
+        if let Some(fork_target_branch_index) = fork_target_branch_index {
             catch_up_branch(
                 fork_target_branch_index,
                 branches,
@@ -419,7 +1122,9 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
                 branch_map_overlays.as_mut_slice(),
                 dirty.as_mut_slice(),
                 repository,
-            );
+                merge_strategy,
+                &messages.merge,
+            )?;
         }
     }
 
@@ -431,14 +1136,431 @@ pub fn backport<E: FnOnce(&[Branch], &[BackportCommit])>(
         branch_map_overlays.as_mut_slice(),
         dirty.as_mut_slice(),
         repository,
-    );
+        merge_strategy,
+        &messages.merge,
+    )?;
 
-    info!("Setting branches...");
-    for (branch, head) in branches.iter().zip(heads.into_iter()) {
-        repository
-            .branch(branch.name().unwrap().unwrap(), &head.unwrap(), true)
-            .unwrap();
+    let mut heads = heads.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+
+    // Other local branches containing rewritten commits, which are updated like siblings if requested.
+    let sibling_count = siblings.len();
+    let mut dependents = vec![];
+    for branch in repository.branches(Some(BranchType::Local))? {
+        let tip = Tip::from_branch(&branch?.0)?;
+        // Backups are meant to keep the old commits.
+        if tip.name.starts_with(BACKUP_PREFIX)
+            || branches
+                .iter()
+                .chain(siblings.iter().map(|(_, sibling)| *sibling))
+                .any(|listed| listed.branch == tip.branch)
+        {
+            continue;
+        }
+        let (_, rewritten) = graph.walk(tip.commit.id(), floor, |id| {
+            map.get(&id).is_some_and(|mapped| mapped.id() != id)
+        })?;
+        if rewritten.is_empty() {
+            continue;
+        }
+        if !update_refs {
+            warn!(
+                "{} contains rewritten commits, but isn't updated.",
+                tip.name
+            );
+            continue;
+        }
+        match based_on(&mut graph, branches, &tip)? {
+            Some(_) => dependents.push(tip),
+            None => warn!(
+                "{} contains rewritten commits, but doesn't contain {}. It isn't updated.",
+                tip.name,
+                branches.last().unwrap().name
+            ),
+        }
+    }
+    // Dependents may be stacked on siblings and the other way around.
+    let siblings = stack_siblings(
+        &mut graph,
+        branches,
+        &siblings
+            .iter()
+            .map(|(_, sibling)| *sibling)
+            .chain(dependents.iter())
+            .collect::<Vec<_>>(),
+    )?;
+    let tips = branches
+        .iter()
+        .chain(siblings.iter().map(|(_, sibling)| *sibling))
+        .collect::<Vec<_>>();
+
+    // Replaced in `stacking_order`, so that siblings are rebased onto the new heads of those they're stacked on.
+    heads.extend(siblings.iter().map(|(_, sibling)| sibling.commit.clone()));
+    for position in stacking_order(&siblings, branches.len()) {
+        let (base_index, sibling) = siblings[position];
+        info!(
+            "Rebasing {} onto {}...",
+            sibling.name, tips[base_index].name
+        );
+        let base = heads[base_index].clone();
+        let follows = map.contains_key(&sibling.commit.id());
+        let mut overlay = HashMap::new();
+        overlay.insert(tips[base_index].commit.id(), base.clone());
+        let mut head = map_commit(
+            sibling.commit.clone(),
+            &overlay,
+            &sibling.name,
+            &mut map,
+            &mut inverse_map,
+            &mut graph,
+            floor,
+            repository,
+            merge_strategy,
+        )?;
+        // Siblings forked below their base's tip are caught up like the branches themselves,
+        // unless they point at a commit of the backport, which they just follow.
+        if !follows && !graph.is_or_has_ancestor(head.id(), base.id())? {
+            let mut merge_index =
+                repository.merge_commits(&head, &base, Some(&merge_strategy.merge_options()))?;
+            let merge_tree = repository.find_tree(write_tree(
+                repository,
+                &mut merge_index,
+                &tips[base_index].name,
+                &sibling.name,
+            )?)?;
+            let signature = repository.signature()?;
+            head = repository.find_commit(repository.commit(
+                None,
+                &signature,
+                &signature,
+                &fill_template(
+                    &messages.merge,
+                    &[("from", &tips[base_index].name), ("into", &sibling.name)],
+                ),
+                &merge_tree,
+                &[&head, &base],
+            )?)?;
+        }
+        heads[branches.len() + position] = head;
+    }
+
+    // Planned up front, so that failing to read a tag leaves all references untouched.
+    let tags = plan_tags(repository, &map, update_tags)?;
+    let mut heads = set_branches(
+        repository,
+        branches,
+        &siblings,
+        &commits,
+        &original_branch_indices,
+        heads,
+        verify,
+        backup,
+        &mut graph,
+    )?;
+    heads.truncate(branches.len() + sibling_count);
+    move_tags(repository, &tags)?;
+    Ok(heads)
+}
+
+/// A tag to move to the new version of the commit it points at.
+struct TagUpdate<'a> {
+    name: String,
+    target: Commit<'a>,
+    /// The tagger and message of an annotated tag, which is recreated with them.
+    annotation: Option<(Signature<'static>, String)>,
+}
+
+/// Collects the tags pointing at rewritten commits if `update`, otherwise only reports them.
+fn plan_tags<'a>(
+    repository: &Repository,
+    map: &HashMap<Oid, Commit<'a>>,
+    update: bool,
+) -> Result<Vec<TagUpdate<'a>>, Error> {
+    let mut updates = vec![];
+    for name in repository.tag_names(None)?.iter().flatten() {
+        let reference_name = format!("refs/tags/{}", name);
+        let target = repository.refname_to_id(&reference_name)?;
+        let tag = repository.find_tag(target).ok();
+        let commit_id = match &tag {
+            Some(tag) if tag.target_type() == Some(ObjectType::Commit) => tag.target_id(),
+            // Tags of tags, trees and blobs.
+            Some(_) => continue,
+            None => target,
+        };
+        let mapped = match map.get(&commit_id) {
+            Some(mapped) if mapped.id() != commit_id => mapped,
+            _ => continue,
+        };
+        if !update {
+            warn!(
+                "Tag {} points at a rewritten commit, but isn't moved.",
+                name
+            );
+            continue;
+        }
+        let annotation = match tag {
+            Some(tag) => {
+                let tagger = match tag.tagger() {
+                    Some(tagger) => tagger.to_owned(),
+                    None => repository.signature()?,
+                };
+                let message = String::from_utf8_lossy(tag.message_bytes().unwrap_or_default());
+                Some((tagger, message.into_owned()))
+            }
+            None => None,
+        };
+        updates.push(TagUpdate {
+            name: name.to_string(),
+            target: mapped.clone(),
+            annotation,
+        });
     }
+    Ok(updates)
+}
 
+/// Moves the tags collected by [`plan_tags`].
+fn move_tags(repository: &Repository, tags: &[TagUpdate]) -> Result<(), Error> {
+    for tag in tags {
+        info!("Moving tag {} to {}...", tag.name, tag.target.id());
+        match &tag.annotation {
+            Some((tagger, message)) => {
+                repository.tag(&tag.name, tag.target.as_object(), tagger, message, true)?;
+            }
+            None => {
+                repository.reference(
+                    &format!("refs/tags/{}", tag.name),
+                    tag.target.id(),
+                    true,
+                    "git-backport: moved tag",
+                )?;
+            }
+        }
+    }
     Ok(())
 }
+
+/// The index of the most junior of `branches` that `tip` contains.
+fn based_on(graph: &mut CommitGraph, branches: &[Tip], tip: &Tip) -> Result<Option<usize>, Error> {
+    for (branch_index, branch) in branches.iter().enumerate() {
+        if graph.is_or_has_ancestor(tip.commit.id(), branch.commit.id())? {
+            return Ok(Some(branch_index));
+        }
+    }
+    Ok(None)
+}
+
+/// Pairs each of `siblings` with the index of the tip it's based on among `branches` followed by `siblings`:
+/// The most junior branch it contains, or another sibling in between that it's stacked on.
+fn stack_siblings<'s, 'a>(
+    graph: &mut CommitGraph,
+    branches: &[Tip],
+    siblings: &[&'s Tip<'a>],
+) -> Result<Vec<(usize, &'s Tip<'a>)>, Error> {
+    let mut stacked = vec![];
+    for &sibling in siblings {
+        let id = sibling.commit.id();
+        let branch_index =
+            based_on(graph, branches, sibling)?.ok_or_else(|| Error::UnorderedChain {
+                descendant: sibling.name.clone(),
+                ancestor: branches.last().unwrap().name.clone(),
+            })?;
+        let (mut base_index, mut base) = (branch_index, branches[branch_index].commit.id());
+        for (position, other) in siblings.iter().enumerate() {
+            let other_id = other.commit.id();
+            if other_id != id
+                && graph.is_or_has_ancestor(id, other_id)?
+                && graph.is_or_has_ancestor(other_id, base)?
+            {
+                base_index = branches.len() + position;
+                base = other_id;
+            }
+        }
+        stacked.push((base_index, sibling));
+    }
+    Ok(stacked)
+}
+
+/// Positions of `siblings` as paired by [`stack_siblings`], each after the sibling it's based on.
+fn stacking_order(siblings: &[(usize, &Tip)], branch_count: usize) -> Vec<usize> {
+    let mut order = Vec::with_capacity(siblings.len());
+    let mut placed = vec![false; siblings.len()];
+    // Bases are strict ancestors, so every pass places at least one sibling.
+    while order.len() < siblings.len() {
+        for (position, &(base_index, _)) in siblings.iter().enumerate() {
+            let base_placed = base_index < branch_count || placed[base_index - branch_count];
+            if !placed[position] && base_placed {
+                placed[position] = true;
+                order.push(position);
+            }
+        }
+    }
+    order
+}
+
+const BACKUP_PREFIX: &str = "git-backport-backup/";
+
+/// Creates a backup branch `git-backport-backup/<name>` for a local branch, numbered if that exists already.
+fn back_up(repository: &Repository, branch: &Tip) {
+    let branch_name = match &branch.branch {
+        Some(branch_name) => branch_name,
+        None => return,
+    };
+    let backup_name = BACKUP_PREFIX.to_string() + branch_name;
+    let mut i = 0usize;
+    while {
+        let backup_name = if i == 0 {
+            Cow::Borrowed(&backup_name)
+        } else {
+            Cow::Owned(backup_name.clone() + "-" + &i.to_string())
+        };
+        repository
+            .branch(backup_name.as_ref(), &branch.commit, false)
+            .is_err()
+    } {
+        i += 1
+    }
+}
+
+/// Maps a side chain commit, preferring its state on the branch it's merged into (`overlay`, named `into`).
+/// Parents are mapped first, using an explicit stack since side chains can be long.
+#[allow(clippy::too_many_arguments)]
+fn map_commit<'a>(
+    commit: Commit<'a>,
+    overlay: &HashMap<Oid, Commit<'a>>,
+    into: &str,
+    map: &mut HashMap<Oid, Commit<'a>>,
+    inverse_map: &mut HashMap<Oid, Commit<'a>>,
+    graph: &mut CommitGraph,
+    floor: u32,
+    repository: &'a Repository,
+    merge_strategy: MergeStrategy,
+) -> Result<Commit<'a>, Error> {
+    let mut stack = vec![commit.clone()];
+    while let Some(top) = stack.last().cloned() {
+        if mapped(&top, overlay, map, graph, floor)?.is_some() {
+            stack.pop();
+            continue;
+        }
+        let parents = top.parents().collect::<Vec<_>>();
+        let mut mapped_parents = vec![];
+        let mut pending = false;
+        for parent in &parents {
+            match mapped(parent, overlay, map, graph, floor)? {
+                Some(mapped_parent) => mapped_parents.push(mapped_parent),
+                None => {
+                    pending = true;
+                    stack.push(parent.clone());
+                }
+            }
+        }
+        if pending {
+            continue;
+        }
+        stack.pop();
+
+        if parents
+            .iter()
+            .zip(mapped_parents.iter())
+            .all(|(a, b)| a.id() == b.id())
+        {
+            map.insert(top.id(), top.clone());
+            inverse_map.insert(top.id(), top);
+            continue;
+        }
+
+        trace!("Rebasing side chain commit {}...", top.id());
+        let mut index = repository.cherrypick_commit(
+            &top,
+            &mapped_parents[0],
+            if parents.len() > 1 { 1 } else { 0 },
+            Some(&merge_strategy.merge_options()),
+        )?;
+        let tree = repository.find_tree(write_tree(repository, &mut index, top.id(), into)?)?;
+        let mapped = repository.find_commit(repository.commit(
+            None,
+            &top.author(),
+            &repository.signature()?,
+            top.message().expect("Couldn't get message of commit"),
+            &tree,
+            mapped_parents.iter().collect::<Vec<_>>().as_slice(),
+        )?)?;
+        map.insert(top.id(), mapped.clone());
+        inverse_map.insert(mapped.id(), top);
+    }
+    Ok(mapped(&commit, overlay, map, graph, floor)?.unwrap())
+}
+
+/// The version of `commit` that [`map_commit`] settled on, if any.
+fn mapped<'a>(
+    commit: &Commit<'a>,
+    overlay: &HashMap<Oid, Commit<'a>>,
+    map: &HashMap<Oid, Commit<'a>>,
+    graph: &mut CommitGraph,
+    floor: u32,
+) -> Result<Option<Commit<'a>>, Error> {
+    if let Some(mapped) = overlay.get(&commit.id()).or_else(|| map.get(&commit.id())) {
+        return Ok(Some(mapped.clone()));
+    }
+    if graph.generation(commit.id())? <= floor {
+        // Can't descend from anything that was rewritten.
+        return Ok(Some(commit.clone()));
+    }
+    Ok(None)
+}
+
+/// Verifies the rewritten tips if requested, then points the branches and siblings at them,
+/// backing them up first if `backup`.
+#[allow(clippy::too_many_arguments)]
+fn set_branches(
+    repository: &Repository,
+    branches: &[Tip],
+    siblings: &[(usize, &Tip)],
+    commits: &[BackportCommit],
+    original_branch_indices: &[usize],
+    heads: Vec<Commit>,
+    verify: bool,
+    backup: bool,
+    graph: &mut CommitGraph,
+) -> Result<Vec<Oid>, Error> {
+    if verify {
+        info!("Verifying...");
+        for (branch_index, (branch, head)) in branches.iter().zip(heads.iter()).enumerate() {
+            let same_commits = commits.iter().zip(original_branch_indices.iter()).all(
+                |(commit, &original_branch_index)| {
+                    (commit.branch_index >= branch_index) == (original_branch_index >= branch_index)
+                },
+            );
+            if same_commits && head.tree_id() != branch.commit.tree_id() {
+                return Err(Error::VerificationFailed {
+                    branch: branch.name.clone(),
+                    reason: "its tree changed",
+                });
+            }
+            if let Some(senior) = heads[..branches.len()].get(branch_index + 1) {
+                if !graph.is_or_has_ancestor(head.id(), senior.id())? {
+                    return Err(Error::VerificationFailed {
+                        branch: branch.name.clone(),
+                        reason: "it doesn't contain the next more senior branch",
+                    });
+                }
+            }
+        }
+    }
+
+    interrupt::begin_updates()?;
+    let tips = branches
+        .iter()
+        .chain(siblings.iter().map(|(_, sibling)| *sibling));
+    if backup {
+        for branch in tips.clone() {
+            back_up(repository, branch);
+        }
+    }
+    info!("Setting branches...");
+    for (branch, head) in tips.zip(heads.iter()) {
+        if let Some(branch_name) = &branch.branch {
+            repository.branch(branch_name, head, true)?;
+        }
+    }
+
+    Ok(heads.iter().map(Commit::id).collect())
+}
@@ -1,20 +1,33 @@
 use {
-    console::{measure_text_width, pad_str, truncate_str, Alignment, Key, Term},
-    git2::{Branch, BranchType, Repository},
-    git_backport::{backport, BackportArgs, BackportCommit},
+    crate::tui::{Tui, EDITING},
+    console::Term,
+    git2::{BranchType, Repository},
+    git_backport::{
+        backport,
+        config::Stack,
+        discover_ancestors,
+        editor::{Accept, Editor},
+        interrupt,
+        rules::Rules,
+        todo::TodoFile,
+        BackportArgs, EmptyCommits, Error, ParentPreference, Tip,
+    },
     log::debug,
-    std::{io::Write, path::PathBuf},
+    std::{path::PathBuf, sync::atomic::Ordering},
     structopt::StructOpt,
 };
 
-//TODO: Implement it by recursively pulling in and mapping commits that have mapped parents (ancestors).
-// That way, loops will behave properly.
-//TODO: How to map new merges? Solution: Merge-mappings per branch. May need additional scanning to check which commits were forks for each branch, to create those merge commits...
+mod tui;
+
+/// Exit code if the backport was canceled. Errors exit with 1 and invalid arguments with 2.
+const EXIT_CANCELED: i32 = 3;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     author,
-    about = "\nInteractively backport commits to ancestor branches.\n\nKnown issues:\n- If you backport past a loop, the paths not taken are currently not rebased."
+    about = "\nInteractively backport commits to ancestor branches.",
+    after_help = "Escape, q or Ctrl-C cancel without modifying any branches and exit with code 3. \
+                  Once branches are being updated, Ctrl-C lets that finish instead."
 )]
 struct Options {
     #[structopt(short, long, default_value = ".", parse(from_os_str))]
@@ -25,9 +38,67 @@ struct Options {
     /// Disables backup branches.
     #[structopt(short = "B", long)]
     no_backup: bool,
+    /// The revspec to backport from. Unless this is a local branch, the new commit ids are printed.
     #[structopt(short, long, default_value = "HEAD")]
     head: String,
-    #[structopt(required = true)]
+    /// Creates a new branch at the rewritten head if it isn't a local branch.
+    #[structopt(short, long)]
+    branch: Option<String>,
+    /// Discovers the ancestor branches automatically.
+    /// Candidates can be limited with --pattern or the `backport.autoPattern` config (multi-valued).
+    #[structopt(short, long, conflicts_with_all = &["ancestors", "stack"])]
+    auto: bool,
+    /// Limits --auto to local branches matching this glob, e.g. `release/*`.
+    #[structopt(short, long, requires = "auto", number_of_values = 1)]
+    pattern: Vec<String>,
+    /// Uses the ancestor branches and defaults of a stack configured as `backport.stack.<name>.*`.
+    /// If the head is part of the stack, only the branches after it are used.
+    #[structopt(short, long, conflicts_with = "ancestors")]
+    stack: Option<String>,
+    /// Which parent to follow if a merge reaches the next branch through several of them: `first-parent` or `fewest-commits`.
+    #[structopt(long, default_value = "first-parent")]
+    parent_preference: ParentPreference,
+    /// Asks which parent to follow if a merge reaches the next branch through several of them.
+    #[structopt(long)]
+    prompt_parents: bool,
+    /// Checks the rewritten branches before updating them.
+    #[structopt(long)]
+    verify: bool,
+    /// Folds commits with `fixup! ` and `squash! ` subjects into the earlier commits they name.
+    #[structopt(long)]
+    autosquash: bool,
+    /// Cherry-picks backported commits onto the more senior branches and merges them forward instead of rewriting history.
+    /// Also enabled by the `backport.stack.<name>.cherryPick` config.
+    #[structopt(long, conflicts_with = "base")]
+    cherry_pick: bool,
+    /// What to do with commits that become empty because their changes are already applied: `drop`, `keep` or `stop`.
+    #[structopt(long, default_value = "drop")]
+    empty: EmptyCommits,
+    /// Assigns commits by editing a todo file with git's editor instead of interactively.
+    #[structopt(long, conflicts_with = "prompt-parents")]
+    todo: bool,
+    /// Backports without editing, as assigned by `Backport-To:` trailers and rules.
+    #[structopt(long, conflicts_with_all = &["todo", "prompt-parents"])]
+    no_edit: bool,
+    /// Ignores the assignment rules configured as `backport.rule.<name>.*`.
+    #[structopt(long)]
+    no_rules: bool,
+    /// Another branch based on one of the ancestor branches or the head, e.g. a feature next to the head.
+    /// Siblings are rebased onto the rewritten branches, but their own commits can't be moved.
+    #[structopt(long, number_of_values = 1)]
+    sibling: Vec<String>,
+    /// Rebases other local branches that contain rewritten commits, instead of only reporting them.
+    #[structopt(long, conflicts_with = "cherry-pick")]
+    update_refs: bool,
+    /// Moves tags pointing at rewritten commits, instead of only reporting them.
+    #[structopt(long, conflicts_with = "cherry-pick")]
+    update_tags: bool,
+    /// Also collects the commits of the most senior branch since this revspec, so that they can be forward-ported,
+    /// i.e. moved to newer branches. The most senior branch is rewritten accordingly.
+    #[structopt(long)]
+    base: Option<String>,
+    /// Ancestor branches, from most junior to most senior. Full refnames (`refs/heads/...`) are accepted.
+    #[structopt(required_unless_one = &["auto", "stack"])]
     ancestors: Vec<String>,
 }
 
@@ -35,6 +106,14 @@ fn main() {
     let options = Options::from_args();
 
     simple_logger::init().unwrap();
+    ctrlc::set_handler(|| {
+        if !EDITING.load(Ordering::SeqCst) && interrupt::request() {
+            Term::stdout().show_cursor().ok();
+            eprintln!("Interrupted.");
+            std::process::exit(EXIT_CANCELED)
+        }
+    })
+    .unwrap();
 
     let repository = if options.no_discovery {
         Repository::open(options.repository)
@@ -42,117 +121,136 @@ fn main() {
         Repository::discover(options.repository)
     }
     .unwrap();
-    let mut branches = vec![if options.head == "HEAD" {
-        let head = repository.head().unwrap();
-        assert!(head.is_branch());
-        Branch::wrap(head)
+    let mut head = Tip::resolve_head(&repository, &options.head).unwrap();
+    if let Some(branch) = options.branch {
+        if head.branch.is_some() {
+            eprintln!("--branch can only be used if the head isn't a local branch.");
+            std::process::exit(2)
+        }
+        if repository.find_branch(&branch, BranchType::Local).is_ok() {
+            eprintln!("Branch {} already exists.", branch);
+            std::process::exit(2)
+        }
+        head.branch = Some(branch);
+    }
+    let stack = options.stack.as_ref().map(|name| {
+        Stack::load(&repository.config().unwrap(), name).unwrap_or_else(|error| exit(error))
+    });
+    let ancestors = if let Some(stack) = &stack {
+        stack
+            .ancestors(&repository, &head)
+            .unwrap_or_else(|error| exit(error))
+    } else if options.auto {
+        let mut patterns = options.pattern;
+        if patterns.is_empty() {
+            let config = repository.config().unwrap();
+            for entry in &config.entries(Some(r"^backport\.autopattern$")).unwrap() {
+                patterns.extend(entry.unwrap().value().map(str::to_string))
+            }
+        }
+        discover_ancestors(&repository, &head, &patterns).unwrap_or_else(|error| exit(error))
     } else {
-        repository
-            .find_branch(&options.head, BranchType::Local)
-            .unwrap()
-    }];
-    for ancestor in options.ancestors.into_iter() {
-        let ancestor = repository
-            .find_branch(&ancestor, BranchType::Local)
-            .unwrap();
-        branches.push(ancestor);
+        options
+            .ancestors
+            .iter()
+            .map(|ancestor| Tip::resolve_branch(&repository, ancestor).unwrap())
+            .collect()
+    };
+    if ancestors.is_empty() {
+        eprintln!("No ancestor branches found.");
+        std::process::exit(2)
     }
+    let mut branches = vec![head];
+    branches.extend(ancestors);
+    if let Some(base) = &options.base {
+        branches.push(Tip::resolve_detached(&repository, base).unwrap());
+    }
+    let siblings = options
+        .sibling
+        .iter()
+        .map(|sibling| Tip::resolve_branch(&repository, sibling).unwrap())
+        .collect::<Vec<_>>();
     debug!(
         "Branches specified: {}",
-        (&branches
+        branches
             .iter()
-            .map(|b| b.name().unwrap().unwrap())
-            .collect::<Vec<_>>())
+            .map(|b| b.name.as_str())
+            .collect::<Vec<_>>()
             .join(", ")
     );
 
-    if let Err(error) = backport(BackportArgs {
-        repository: &repository,
-        backup: !options.no_backup,
-        branches: branches.as_slice(),
-        edit: |branches, commits| {
-            let mut out = Term::stdout();
-            let mut cursor = 0;
-            let (_, width) = out.size();
-            let width = width as usize;
-            dbg!(width);
-            loop {
-                for (
-                    i,
-                    BackportCommit {
-                        commit,
-                        branch_index,
-                    },
-                ) in commits.iter().enumerate()
-                {
-                    let branch_index = *branch_index.borrow();
-                    out.write_all(pad_str("", branch_index, Alignment::Left, None).as_bytes())
-                        .unwrap();
-                    out.write_all(if cursor == i { b">" } else { b" " })
-                        .unwrap();
-                    out.write_all(truncate_str(&commit.id().to_string(), 8, "").as_bytes())
-                        .unwrap();
-                    out.write_all(b" ").unwrap();
-                    let branch_name = truncate_str(
-                        branches[branch_index].name().unwrap().unwrap(),
-                        width / 2,
-                        "...",
-                    );
-                    let branch_name_width = measure_text_width(branch_name.as_ref());
-                    out.write_all(branch_name.as_bytes()).unwrap();
-                    out.write_all(b" ").unwrap();
-                    out.write_line(
-                        pad_str(
-                            commit
-                                .message()
-                                .unwrap()
-                                .split('\r')
-                                .next()
-                                .unwrap()
-                                .split('\n')
-                                .next()
-                                .unwrap(),
-                            width - (branch_index + 1 + 8 + 1 + branch_name_width + 1),
-                            Alignment::Left,
-                            Some("..."),
-                        )
-                        .as_ref(),
-                    )
-                    .unwrap();
-                }
-                {
-                    let branch_index = &commits[cursor].branch_index;
-                    use Key::*;
-                    match out.read_key().unwrap() {
-                        ArrowLeft => {
-                            if *branch_index.borrow() > 0 {
-                                *branch_index.borrow_mut() -= 1
-                            }
-                        }
-                        ArrowRight => {
-                            if *branch_index.borrow() < branches.len() - 1 {
-                                *branch_index.borrow_mut() += 1
-                            }
-                        }
-                        ArrowUp => {
-                            if cursor > 0 {
-                                cursor -= 1
-                            }
-                        }
-                        ArrowDown => {
-                            if cursor < commits.len() - 1 {
-                                cursor += 1
-                            }
-                        }
-                        Enter => break,
-                        Escape => panic!(),
-                        _ => (),
-                    }
+    let mut tui = Tui::new(options.prompt_parents);
+    let mut todo_file;
+    let mut editor: &mut dyn Editor = if options.todo {
+        todo_file = TodoFile::for_repository(&repository).unwrap();
+        &mut todo_file
+    } else if options.no_edit {
+        &mut Accept
+    } else {
+        &mut tui
+    };
+    let mut preassigned;
+    if !options.no_rules {
+        let rules = Rules::load(&repository.config().unwrap()).unwrap_or_else(|error| exit(error));
+        if !rules.rules.is_empty() {
+            preassigned = rules.before(editor);
+            editor = &mut preassigned;
+        }
+    }
+
+    let mut args = match &stack {
+        Some(stack) => stack.backport_args(&repository, &branches, editor),
+        None => BackportArgs {
+            repository: &repository,
+            backup: true,
+            branches: &branches,
+            siblings: &[],
+            merge_strategy: Default::default(),
+            messages: Default::default(),
+            parent_preference: Default::default(),
+            verify: false,
+            autosquash: false,
+            cherry_pick: false,
+            empty: Default::default(),
+            update_refs: false,
+            update_tags: false,
+            editor,
+        },
+    };
+    if options.no_backup {
+        args.backup = false;
+    }
+    args.parent_preference = options.parent_preference;
+    args.verify = options.verify;
+    args.siblings = &siblings;
+    args.autosquash = options.autosquash;
+    args.empty = options.empty;
+    args.update_refs = options.update_refs;
+    args.update_tags = options.update_tags;
+    if options.cherry_pick {
+        args.cherry_pick = true;
+    }
+
+    let result = backport(args);
+    if interrupt::requested_while_updating() {
+        eprintln!("Interrupted while updating branches, which were updated anyway.");
+    }
+    match result {
+        Ok(heads) => {
+            for (branch, head) in branches.iter().zip(heads) {
+                if branch.branch.is_none() {
+                    println!("{} {}", head, branch.name)
                 }
-                out.move_cursor_up(commits.len()).unwrap()
             }
-        },
-    }) {
-        match error {}
+        }
+        Err(error) => exit(error),
     }
 }
+
+fn exit(error: Error) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(match error {
+        Error::Canceled => EXIT_CANCELED,
+        _ => 1,
+    })
+}
@@ -0,0 +1,143 @@
+//! Detects commits whose changes are already on a more senior branch, e.g. because they were cherry-picked there
+//! before, by comparing patch ids like `git cherry` does.
+
+use {
+    crate::{BackportCommit, Tip},
+    git2::{Commit, DiffFormat, DiffOptions, Error, ObjectType, Oid, Repository, Sort},
+    log::trace,
+    std::collections::{BTreeSet, HashMap},
+};
+
+/// Hashes `commit`'s changes relative to its parent, ignoring whitespace and line numbers.
+/// Merge commits have no patch id.
+fn patch_id(repository: &Repository, commit: &Commit) -> Result<Option<Oid>, Error> {
+    if commit.parent_count() != 1 {
+        return Ok(None);
+    }
+    let diff = repository.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        None,
+    )?;
+    let mut text = vec![];
+    diff.print(DiffFormat::PatchId, |delta, _, line| {
+        match line.origin() {
+            // Hunk headers only differ in line numbers.
+            'H' => (),
+            // Binary changes are printed without their contents.
+            'B' => text.extend_from_slice(delta.new_file().id().as_bytes()),
+            origin => {
+                text.push(origin as u8);
+                text.extend(
+                    line.content()
+                        .iter()
+                        .filter(|byte| !byte.is_ascii_whitespace()),
+                );
+            }
+        }
+        true
+    })?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    Oid::hash_object(ObjectType::Blob, &text).map(Some)
+}
+
+/// The paths `commit` changes relative to its parent.
+fn changed_paths(repository: &Repository, commit: &Commit) -> Result<Vec<String>, Error> {
+    let diff = repository.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        None,
+    )?;
+    Ok(diff
+        .deltas()
+        .flat_map(|delta| vec![delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter_map(|path| path.to_str().map(str::to_string))
+        .collect())
+}
+
+/// Whether `commit` changes any of the paths `options` is limited to.
+fn touches(
+    repository: &Repository,
+    commit: &Commit,
+    options: &mut DiffOptions,
+) -> Result<bool, Error> {
+    let diff = repository.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        Some(options),
+    )?;
+    Ok(diff.deltas().len() > 0)
+}
+
+/// Sets [`BackportCommit::duplicate`] for commits that have an equivalent on a more senior branch.
+///
+/// The most senior branch's whole history is searched, since commit dates can't be relied on to bound it:
+/// Clocks are skewed and rebases keep author dates. Only commits touching the same paths are hashed.
+pub(crate) fn mark_duplicates(
+    repository: &Repository,
+    branches: &[Tip],
+    commits: &mut [BackportCommit],
+) -> Result<(), Error> {
+    let patch_ids = commits
+        .iter()
+        .map(|commit| patch_id(repository, &commit.commit))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut paths = BTreeSet::new();
+    for (commit, patch_id) in commits.iter().zip(patch_ids.iter()) {
+        if patch_id.is_some() {
+            paths.extend(changed_paths(repository, &commit.commit)?);
+        }
+    }
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut options = DiffOptions::new();
+    options.disable_pathspec_match(true);
+    for path in &paths {
+        options.pathspec(path);
+    }
+
+    // The most senior commit with each patch id, by branch index.
+    let mut originals = HashMap::new();
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+    revwalk.push(branches.last().unwrap().commit.id())?;
+    for id in revwalk {
+        let commit = repository.find_commit(id?)?;
+        if commit.parent_count() != 1 || !touches(repository, &commit, &mut options)? {
+            continue;
+        }
+        if let Some(patch_id) = patch_id(repository, &commit)? {
+            originals
+                .entry(patch_id)
+                .or_insert((branches.len() - 1, commit.id()));
+        }
+    }
+
+    // Commits are listed from the most junior branch to the most senior one.
+    for (commit, patch_id) in commits.iter_mut().zip(patch_ids).rev() {
+        let patch_id = match patch_id {
+            Some(patch_id) => patch_id,
+            None => continue,
+        };
+        match originals.get(&patch_id) {
+            Some(&(branch_index, original)) if branch_index > commit.branch_index => {
+                trace!(
+                    "Found duplicate {} of {} on {}.",
+                    commit.commit.id(),
+                    original,
+                    branches[branch_index].name
+                );
+                commit.duplicate = Some(original);
+            }
+            Some(_) => (),
+            None => {
+                originals.insert(patch_id, (commit.branch_index, commit.commit.id()));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! Assignment of commits by rules, either as an [`Editor`] on its own or ahead of another one.
+//!
+//! Rules can be configured in git config, e.g.:
+//!
+//! ```ini
+//! [backport "rule.ci"]
+//!     path = ci/
+//!     path = LICENSE
+//!     branch = *
+//! [backport "rule.docs"]
+//!     path = docs/
+//!     branch = release/*
+//! [backport "rule.tagged"]
+//!     message = [backport:1.x]
+//!     trailer = Backport-Area: build
+//!     branch = release/1.x
+//! ```
+//!
+//! `path`, `message` and `trailer` may each be given multiple times, and any of them matching moves a commit.
+//! `branch` is a glob selecting the most senior matching branch of the backport, so `*` is always the most senior one.
+//! Rules are tried in the order they appear in, and take precedence over `Backport-To:` trailers.
+
+use {
+    crate::{
+        editor::{EditOutcome, Editor, EditorError, Plan, Problem},
+        Error, ParentCandidate,
+    },
+    git2::{Commit, Config, Repository},
+    std::collections::HashMap,
+};
+
+pub enum Matcher {
+    /// The commit message contains this text.
+    Message(String),
+    /// The commit changes a path starting with this prefix, compared to its first parent.
+    Path(String),
+    /// The commit message has this trailer. Keys are compared case-insensitively.
+    Trailer { key: String, value: String },
+}
+
+pub struct Rule {
+    pub matcher: Matcher,
+    /// Name or glob of the branch matching commits are moved to. If several branches match, the most senior one is used.
+    pub branch: String,
+}
+
+/// Moves each commit to the branch of the first rule matching it.
+/// Rules for branches that aren't part of the backport are ignored, and commits matching no rule keep their branch.
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Loads the rules configured as `backport.rule.<name>.*`.
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        let mut branches = HashMap::new();
+        let mut matchers = vec![];
+        for entry in
+            &config.entries(Some(r"^backport\.rule\..*\.(path|message|trailer|branch)$"))?
+        {
+            let entry = entry?;
+            let key = entry.name().unwrap_or_default().to_string();
+            let invalid = || Error::InvalidConfig {
+                key: key.clone(),
+                value: String::from_utf8_lossy(entry.value_bytes()).into_owned(),
+            };
+            let value = entry.value().ok_or_else(invalid)?.to_string();
+            let (name, variable) = key["backport.rule.".len()..]
+                .rsplit_once('.')
+                .ok_or_else(invalid)?;
+            let matcher = match variable {
+                "branch" => {
+                    branches.insert(name.to_string(), value);
+                    continue;
+                }
+                "path" => Matcher::Path(value),
+                "message" => Matcher::Message(value),
+                _ => match value.split_once(':') {
+                    Some((key, value)) => Matcher::Trailer {
+                        key: key.trim().to_string(),
+                        value: value.trim().to_string(),
+                    },
+                    None => return Err(invalid()),
+                },
+            };
+            matchers.push((name.to_string(), matcher));
+        }
+
+        let mut rules = vec![];
+        for (name, matcher) in matchers {
+            let branch = branches
+                .get(&name)
+                .ok_or_else(|| Error::InvalidConfig {
+                    key: format!("backport.rule.{}.branch", name),
+                    value: String::new(),
+                })?
+                .clone();
+            rules.push(Rule { matcher, branch });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Applies the rules to every commit of the plan.
+    pub fn apply(&self, plan: &mut Plan) -> Result<(), git2::Error> {
+        for position in 0..plan.commits.len() {
+            if plan.commits[position].fold.is_some() {
+                continue;
+            }
+            for rule in &self.rules {
+                let branch_index = match plan
+                    .branches
+                    .iter()
+                    .rposition(|b| glob_matches(&rule.branch, &b.name))
+                {
+                    Some(branch_index) => branch_index,
+                    None => continue,
+                };
+                if rule
+                    .matcher
+                    .matches(plan.repository, &plan.commits[position].commit)?
+                {
+                    plan.commits[position].branch_index = branch_index;
+                    break;
+                }
+            }
+        }
+        // Folded commits go wherever the commit they're folded into went.
+        for position in 0..plan.commits.len() {
+            let into = match plan.commits[position].fold {
+                Some(fold) => fold.into,
+                None => continue,
+            };
+            if let Some(target) = plan.commits.iter().position(|c| c.commit.id() == into) {
+                plan.commits[position].branch_index = plan.commits[target].branch_index;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the rules before `editor` sees the plan for the first time.
+    pub fn before<E: Editor>(self, editor: E) -> Preassigned<E> {
+        Preassigned {
+            rules: self,
+            editor,
+            applied: false,
+        }
+    }
+}
+
+impl Matcher {
+    pub fn matches(&self, repository: &Repository, commit: &Commit) -> Result<bool, git2::Error> {
+        Ok(match self {
+            Matcher::Message(text) => commit.message().unwrap_or_default().contains(text.as_str()),
+            Matcher::Path(prefix) => {
+                let parent_tree = match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree()?),
+                    Err(_) => None,
+                };
+                let diff = repository.diff_tree_to_tree(
+                    parent_tree.as_ref(),
+                    Some(&commit.tree()?),
+                    None,
+                )?;
+                diff.deltas().any(|delta| {
+                    [delta.old_file().path(), delta.new_file().path()]
+                        .iter()
+                        .flatten()
+                        .any(|path| path.to_string_lossy().starts_with(prefix.as_str()))
+                })
+            }
+            Matcher::Trailer { key, value } => trailers(commit.message().unwrap_or_default())
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value),
+        })
+    }
+}
+
+/// Parses the `Key: value` lines of a message's last paragraph, if it consists only of those (and their continuations).
+pub fn trailers(message: &str) -> Vec<(&str, &str)> {
+    let message = message.trim_end();
+    let paragraph = match message.rsplit_once("\n\n") {
+        Some((_, paragraph)) => paragraph,
+        // The subject alone is never a trailer block.
+        None => return vec![],
+    };
+    let mut trailers = vec![];
+    for line in paragraph.lines() {
+        match line.split_once(':') {
+            Some((key, value))
+                if !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-') =>
+            {
+                trailers.push((key, value.trim()))
+            }
+            _ if line.starts_with(char::is_whitespace) && !trailers.is_empty() => (),
+            _ => return vec![],
+        }
+    }
+    trailers
+}
+
+/// Matches `text` against a pattern where `*` stands for any number of characters and `?` for exactly one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (
+        pattern.chars().collect::<Vec<_>>(),
+        text.chars().collect::<Vec<_>>(),
+    );
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Editor for Rules {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        if let Some(problem) = problems.first() {
+            // Applying the same rules again wouldn't change anything.
+            return Err(format!("The rules produced an invalid plan: {}", problem).into());
+        }
+        self.apply(plan)?;
+        Ok(EditOutcome::Apply)
+    }
+}
+
+/// An editor that gets a plan with [`Rules`] already applied, see [`Rules::before`].
+pub struct Preassigned<E: Editor> {
+    pub rules: Rules,
+    pub editor: E,
+    applied: bool,
+}
+
+impl<E: Editor> Editor for Preassigned<E> {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        if !self.applied {
+            self.rules.apply(plan)?;
+            self.applied = true;
+        }
+        self.editor.edit(plan, problems)
+    }
+
+    fn confirm(&mut self, plan: &Plan) -> Result<bool, EditorError> {
+        self.editor.confirm(plan)
+    }
+
+    fn choose_parent(
+        &mut self,
+        commit: &Commit,
+        candidates: &[ParentCandidate],
+    ) -> Result<Option<usize>, EditorError> {
+        self.editor.choose_parent(commit, candidates)
+    }
+}
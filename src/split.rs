@@ -0,0 +1,180 @@
+//! Splitting commits by hunk, so that parts of a commit can be assigned to different branches.
+//!
+//! See [`Plan::hunks`](crate::editor::Plan::hunks) and [`Plan::split`](crate::editor::Plan::split).
+
+use {
+    git2::{Commit, Delta, Diff, Error, Index, IndexEntry, IndexTime, Oid, Patch, Repository},
+    std::path::PathBuf,
+};
+
+/// A unit of a commit's changes that can be moved on its own:
+/// A hunk of a text file, or the whole change to a binary file or a file's mode.
+pub struct Hunk {
+    pub path: PathBuf,
+    /// The hunk header, e.g. `@@ -1,2 +1,3 @@`, or a description of the whole-file change.
+    pub header: String,
+    /// The hunk's lines, prefixed with ` `, `+` or `-`.
+    pub lines: Vec<String>,
+    /// The branch the hunk is assigned to, initially that of its commit.
+    pub branch_index: usize,
+    delta: usize,
+    /// `None` for whole-file changes.
+    hunk: Option<usize>,
+}
+
+/// The changes of `commit` relative to its first parent. Hunks are identified by their position in this diff.
+fn diff<'a>(repository: &'a Repository, commit: &Commit) -> Result<Diff<'a>, Error> {
+    repository.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        None,
+    )
+}
+
+pub(crate) fn hunks(
+    repository: &Repository,
+    commit: &Commit,
+    branch_index: usize,
+) -> Result<Vec<Hunk>, Error> {
+    let diff = diff(repository, commit)?;
+    let mut hunks = vec![];
+    for (delta_index, delta) in diff.deltas().enumerate() {
+        // Without rename detection, both sides have the same path.
+        let path = delta.new_file().path().unwrap().to_path_buf();
+        let patch = match Patch::from_diff(&diff, delta_index)? {
+            // Binary files and mode changes have no hunks.
+            Some(patch) if patch.num_hunks() > 0 => patch,
+            _ => {
+                hunks.push(Hunk {
+                    path,
+                    header: match delta.status() {
+                        Delta::Added => "new file",
+                        Delta::Deleted => "deleted file",
+                        _ => "changed file",
+                    }
+                    .to_string(),
+                    lines: vec![],
+                    branch_index,
+                    delta: delta_index,
+                    hunk: None,
+                });
+                continue;
+            }
+        };
+        for hunk_index in 0..patch.num_hunks() {
+            let (hunk, line_count) = patch.hunk(hunk_index)?;
+            let mut lines = vec![];
+            for line_index in 0..line_count {
+                let line = patch.line_in_hunk(hunk_index, line_index)?;
+                if let origin @ (' ' | '+' | '-') = line.origin() {
+                    lines.push(format!(
+                        "{}{}",
+                        origin,
+                        String::from_utf8_lossy(line.content()).trim_end_matches('\n')
+                    ));
+                }
+            }
+            hunks.push(Hunk {
+                path: path.clone(),
+                header: String::from_utf8_lossy(hunk.header())
+                    .trim_end()
+                    .to_string(),
+                lines,
+                branch_index,
+                delta: delta_index,
+                hunk: Some(hunk_index),
+            });
+        }
+    }
+    Ok(hunks)
+}
+
+/// Writes the tree of `commit`'s first parent with only the selected hunks applied.
+pub(crate) fn apply(
+    repository: &Repository,
+    commit: &Commit,
+    hunks: &[Hunk],
+    selected: impl Fn(&Hunk) -> bool,
+) -> Result<Oid, Error> {
+    let diff = diff(repository, commit)?;
+    let (old_tree, new_tree) = (commit.parent(0)?.tree()?, commit.tree()?);
+    let mut index = Index::new()?;
+    index.read_tree(&old_tree)?;
+
+    for (delta_index, delta) in diff.deltas().enumerate() {
+        let file_hunks = hunks
+            .iter()
+            .filter(|hunk| hunk.delta == delta_index)
+            .collect::<Vec<_>>();
+        if !file_hunks.iter().any(|hunk| selected(hunk)) {
+            continue;
+        }
+        let (old, new) = (delta.old_file(), delta.new_file());
+        let path = new.path().unwrap();
+        let whole = file_hunks.iter().all(|hunk| selected(hunk));
+        if whole && new.id().is_zero() {
+            index.remove_path(path)?;
+            continue;
+        }
+        let id = if whole {
+            new.id()
+        } else {
+            let patch = Patch::from_diff(&diff, delta_index)?.unwrap();
+            let old_content = if old.id().is_zero() {
+                vec![]
+            } else {
+                repository.find_blob(old.id())?.content().to_vec()
+            };
+            let old_lines = old_content
+                .split_inclusive(|&b| b == b'\n')
+                .collect::<Vec<_>>();
+            let mut content = vec![];
+            // Old lines up to here have been copied or replaced.
+            let mut copied = 0;
+            for hunk in file_hunks {
+                let hunk_index = hunk.hunk.unwrap();
+                let (header, line_count) = patch.hunk(hunk_index)?;
+                // Hunks without old lines insert after their start line rather than at it.
+                let start = header.old_start() as usize - (header.old_lines() > 0) as usize;
+                for line in &old_lines[copied..start] {
+                    content.extend_from_slice(line);
+                }
+                copied = start;
+                if !selected(hunk) {
+                    continue;
+                }
+                for line_index in 0..line_count {
+                    let line = patch.line_in_hunk(hunk_index, line_index)?;
+                    if let ' ' | '+' = line.origin() {
+                        content.extend_from_slice(line.content());
+                    }
+                }
+                copied += header.old_lines() as usize;
+            }
+            for line in &old_lines[copied..] {
+                content.extend_from_slice(line);
+            }
+            repository.blob(&content)?
+        };
+        index.add(&IndexEntry {
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: if new.id().is_zero() {
+                old_tree.get_path(path)?
+            } else {
+                new_tree.get_path(path)?
+            }
+            .filemode() as u32,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id,
+            flags: 0,
+            flags_extended: 0,
+            path: path.to_string_lossy().as_bytes().to_vec(),
+        })?;
+    }
+    index.write_tree_to(repository)
+}
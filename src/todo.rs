@@ -0,0 +1,294 @@
+//! An [`Editor`] that lets the user assign commits in a text file, like `git rebase --interactive`:
+//!
+//! ```text
+//! develop 1a2b3c4d Fix the frobnicator
+//! feature 5e6f7a8b Add a frobnicator setting
+//! ```
+//!
+//! Each line starts with the branch (name or index) and the commit (id or unique prefix).
+//! The order of lines doesn't matter, commits without a line keep their branch and an empty file cancels.
+//! Instead of a branch, `fixup` or `squash` folds the commit into that of the nearest line above without either.
+//! `reword` (or `r`) before the branch keeps the commit's new message, or asks for one:
+//!
+//! ```text
+//! reword develop 1a2b3c4d Fix the frobnicator (backport of #123)
+//! ```
+
+use {
+    crate::{
+        editor::{EditOutcome, Editor, EditorError, Plan, Problem},
+        BackportCommit, Fold, FoldKind,
+    },
+    git2::{ErrorCode, Repository},
+    std::{
+        fs,
+        path::{Path, PathBuf},
+        process::Command,
+    },
+};
+
+pub struct TodoFile {
+    pub path: PathBuf,
+    /// Shell command the file's path is passed to, e.g. `vi`.
+    pub editor: String,
+}
+
+/// Git's editor, i.e. the first of `GIT_EDITOR`, `core.editor`, `VISUAL` and `EDITOR` that's set, or `vi`.
+pub fn git_editor(repository: &Repository) -> Result<String, git2::Error> {
+    Ok(match std::env::var("GIT_EDITOR") {
+        Ok(editor) => editor,
+        Err(_) => match repository.config()?.get_string("core.editor") {
+            Ok(editor) => editor,
+            Err(error) if error.code() == ErrorCode::NotFound => std::env::var("VISUAL")
+                .or_else(|_| std::env::var("EDITOR"))
+                .unwrap_or_else(|_| "vi".to_string()),
+            Err(error) => return Err(error),
+        },
+    })
+}
+
+/// Runs the shell command `editor` with `path` as argument.
+fn run_editor(editor: &str, path: &Path) -> Result<(), EditorError> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor, status).into());
+    }
+    Ok(())
+}
+
+/// Lets the user edit a commit message in `editor` using a file at `path`, which is removed afterwards.
+/// Like with `git commit`, lines starting with `#` are dropped. Returns `None` if the message ends up empty.
+pub fn edit_message(
+    editor: &str,
+    path: &Path,
+    message: &str,
+    help: &str,
+) -> Result<Option<String>, EditorError> {
+    let mut text = message.trim_end().to_string() + "\n\n";
+    for line in help.lines() {
+        text += &format!("# {}\n", line);
+    }
+    fs::write(path, text)?;
+    run_editor(editor, path)?;
+    let text = fs::read_to_string(path)?;
+    fs::remove_file(path)?;
+    let message = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let message = message.trim();
+    Ok(if message.is_empty() {
+        None
+    } else {
+        Some(message.to_string() + "\n")
+    })
+}
+
+/// Lets the user edit the message `commit` is backported with in `editor`, using a file at `path`.
+/// An empty message keeps the current one, and the original message undoes rewording.
+pub fn reword(editor: &str, path: &Path, commit: &mut BackportCommit) -> Result<(), EditorError> {
+    let help = format!(
+        "Enter the message for {} when it's backported.\n\
+         Lines starting with '#' are ignored, and an empty message keeps the current one.",
+        commit.commit.id()
+    );
+    if let Some(message) = edit_message(editor, path, commit.message(), &help)? {
+        let original = commit.commit.message().unwrap_or_default();
+        commit.message = if message.trim_end() == original.trim_end() {
+            None
+        } else {
+            Some(message)
+        };
+    }
+    Ok(())
+}
+
+/// A line of the todo file.
+struct Line {
+    position: usize,
+    branch_index: usize,
+    fold: Option<Fold>,
+    reword: bool,
+}
+
+impl TodoFile {
+    /// Uses `BACKPORT_TODO` in the repository's git directory and [`git_editor`].
+    pub fn for_repository(repository: &Repository) -> Result<Self, git2::Error> {
+        Ok(Self {
+            path: repository.path().join("BACKPORT_TODO"),
+            editor: git_editor(repository)?,
+        })
+    }
+
+    fn write(&self, plan: &Plan, problems: &[Problem]) -> Result<(), EditorError> {
+        let mut text = String::new();
+        for problem in problems {
+            text += &format!("# Error: {}\n", problem);
+        }
+        for commit in plan.commits.iter().rev() {
+            if let Some(duplicate) = commit.duplicate {
+                text += &format!("# Duplicate of {}:\n", &duplicate.to_string()[..8]);
+            }
+            text += &format!(
+                "{} {} {}\n",
+                match commit.fold.map(|fold| fold.kind) {
+                    Some(FoldKind::Fixup) => "fixup".to_string(),
+                    Some(FoldKind::Squash) => "squash".to_string(),
+                    None => {
+                        let branch = plan
+                            .branches
+                            .get(commit.branch_index)
+                            .map_or_else(|| commit.branch_index.to_string(), |b| b.name.clone());
+                        if commit.message.is_some() {
+                            format!("reword {}", branch)
+                        } else {
+                            branch
+                        }
+                    }
+                },
+                &commit.commit.id().to_string()[..8],
+                commit.message().lines().next().unwrap_or_default()
+            );
+        }
+        text += "\n# Branches, from most junior to most senior:\n";
+        for (index, branch) in plan.branches.iter().enumerate() {
+            text += &format!("#  {} {}\n", index, branch.name);
+        }
+        text += "#\n# Change the first word of a line to move that commit. Commits are listed oldest first.\n";
+        text += "# fixup or squash instead of a branch folds the commit into the one above, discarding or keeping its message.\n";
+        text +=
+            "# reword (or r) before the branch keeps the commit's new message, or asks for one.\n";
+        text += "# Duplicates of commits on more senior branches are dropped where those are already applied.\n";
+        text += "# An empty file cancels the backport.\n";
+        fs::write(&self.path, text)?;
+        Ok(())
+    }
+
+    /// Replaces the errors at the top of the file, keeping the user's edits.
+    fn annotate(&self, errors: &[String]) -> Result<(), EditorError> {
+        let mut text = errors
+            .iter()
+            .map(|error| format!("# Error: {}\n", error))
+            .collect::<String>();
+        for line in fs::read_to_string(&self.path)?.lines() {
+            if !line.starts_with("# Error: ") {
+                text += line;
+                text += "\n";
+            }
+        }
+        fs::write(&self.path, text)?;
+        Ok(())
+    }
+
+    /// Parses the edited file. Invalid lines are reported in `errors`.
+    fn read(&self, plan: &Plan, errors: &mut Vec<String>) -> Result<Vec<Line>, EditorError> {
+        let text = fs::read_to_string(&self.path)?;
+        let mut assignments = vec![];
+        // The commit and branch of the nearest line above that isn't folded.
+        let mut above = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace().peekable();
+            let reword = matches!(words.peek(), Some(&"reword") | Some(&"r"));
+            if reword {
+                words.next();
+            }
+            let (branch, id) = (
+                words.next().unwrap_or_default(),
+                words.next().unwrap_or_default(),
+            );
+            let kind = match branch {
+                "fixup" => Some(FoldKind::Fixup),
+                "squash" => Some(FoldKind::Squash),
+                _ => None,
+            };
+            if reword && kind.is_some() {
+                errors.push(format!(
+                    "line {}: {} commits can't be reworded",
+                    number + 1,
+                    branch
+                ));
+                continue;
+            }
+            let branch_index = match kind {
+                Some(_) => above.map(|(_, branch_index)| branch_index),
+                None => plan.branch_index(branch),
+            };
+            match (branch_index, plan.position(id)) {
+                (Some(branch_index), Some(position)) => {
+                    let fold = match kind {
+                        Some(kind) => Some(Fold {
+                            kind,
+                            into: above.unwrap().0,
+                        }),
+                        None => {
+                            above = Some((plan.commits[position].commit.id(), branch_index));
+                            None
+                        }
+                    };
+                    assignments.push(Line {
+                        position,
+                        branch_index,
+                        fold,
+                        reword,
+                    })
+                }
+                (None, _) if kind.is_some() => errors.push(format!(
+                    "line {}: {} needs a commit on a line above to fold into",
+                    number + 1,
+                    branch
+                )),
+                (None, _) => errors.push(format!("line {}: unknown branch {}", number + 1, branch)),
+                (_, None) => errors.push(format!(
+                    "line {}: {} doesn't identify a commit of this backport",
+                    number + 1,
+                    id
+                )),
+            }
+        }
+        Ok(assignments)
+    }
+}
+
+impl Editor for TodoFile {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.write(plan, problems)?;
+        loop {
+            run_editor(&self.editor, &self.path)?;
+            let mut errors = vec![];
+            let lines = self.read(plan, &mut errors)?;
+            if !errors.is_empty() {
+                self.annotate(&errors)?;
+                continue;
+            }
+            fs::remove_file(&self.path)?;
+            if lines.is_empty() {
+                return Ok(EditOutcome::Cancel);
+            }
+            let message_path = self.path.with_file_name("BACKPORT_EDITMSG");
+            for line in lines {
+                let commit = &mut plan.commits[line.position];
+                commit.branch_index = line.branch_index;
+                commit.fold = line.fold;
+                if line.reword {
+                    if commit.message.is_none() {
+                        reword(&self.editor, &message_path, commit)?;
+                    }
+                } else if line.fold.is_none() {
+                    // Folded commits' messages can't be shown, so they're kept.
+                    commit.message = None;
+                }
+            }
+            return Ok(EditOutcome::Apply);
+        }
+    }
+}
@@ -0,0 +1,746 @@
+//! The interactive editor: A full-screen list of the commits with a column per branch, above a preview of the selected commit.
+
+use {
+    console::{measure_text_width, pad_str, style, truncate_str, Alignment, Key, Term},
+    git2::{Commit, DiffStatsFormat, Oid, Repository},
+    git_backport::{
+        conflicts::ConflictPredictor,
+        editor::{EditOutcome, Editor, EditorError, Plan, Problem},
+        rules::Matcher,
+        split::Hunk,
+        todo::{git_editor, reword},
+        Fold, FoldKind, ParentCandidate,
+    },
+    log::debug,
+    std::{
+        collections::{hash_map::Entry, BTreeSet, HashMap},
+        io,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Whether Ctrl-C is handled by the editor, which cancels the backport when its key read is interrupted.
+pub static EDITING: AtomicBool = AtomicBool::new(false);
+
+const FOOTER: &str =
+    "←→/hl move  0-9 to branch  ↑↓/jk  ^U/^D/g/G scroll  v range  b to bottom  / path  s split  \
+                      f/F fixup/squash  r reword  u/^R undo/redo  Enter apply  q cancel";
+
+const SPLIT_FOOTER: &str = "←→/hl move hunk  0-9 to branch  ↑↓/jk  Enter split  Esc back";
+
+/// Which commits branch changes apply to.
+#[derive(Default)]
+enum Selection {
+    /// Only the one under the cursor.
+    #[default]
+    None,
+    /// Everything between this position and the cursor.
+    Range(usize),
+    Set(BTreeSet<usize>),
+}
+
+/// The commit being split and where its hunks are assigned.
+struct Splitting {
+    position: usize,
+    hunks: Vec<Hunk>,
+    cursor: usize,
+    scroll: usize,
+}
+
+#[derive(Default)]
+pub struct Tui {
+    pub prompt_parents: bool,
+    /// Set if a prompt was interrupted, which can't cancel by itself.
+    canceled: bool,
+    cursor: usize,
+    scroll: usize,
+    /// The branches commits were found on, to highlight moved ones.
+    original: Vec<usize>,
+    diffstats: HashMap<Oid, Vec<String>>,
+    selection: Selection,
+    /// Previous assignments, most recent last.
+    undo: Vec<Assignments>,
+    redo: Vec<Assignments>,
+    /// Replaces the key bindings while prompting for input.
+    prompt: Option<String>,
+    /// `None` if prediction isn't possible.
+    predictor: Option<ConflictPredictor>,
+    /// The assignments `conflicts` were predicted for.
+    predicted: Assignments,
+    conflicts: Vec<Option<usize>>,
+    splitting: Option<Splitting>,
+}
+
+/// Routes Ctrl-C to the editor and hides the cursor until dropped, including when panicking.
+/// Full-screen editing happens on the alternate screen, which restores the previous terminal contents when left.
+struct Editing {
+    term: Term,
+    screen: bool,
+}
+
+impl Editing {
+    fn start(term: &Term, screen: bool) -> io::Result<Self> {
+        EDITING.store(true, Ordering::SeqCst);
+        let screen = screen && term.is_term();
+        let editing = Self {
+            term: term.clone(),
+            screen,
+        };
+        if screen {
+            term.write_str("\x1b[?1049h")?;
+        }
+        term.hide_cursor()?;
+        Ok(editing)
+    }
+
+    /// Restores the terminal while `f` runs, e.g. for an external editor.
+    /// Ctrl-C is still routed to the editor, so it doesn't exit while a program it started handles it.
+    fn suspended<T>(&self, f: impl FnOnce() -> T) -> io::Result<T> {
+        self.term.show_cursor()?;
+        if self.screen {
+            self.term.write_str("\x1b[?1049l")?;
+        }
+        let result = f();
+        if self.screen {
+            self.term.write_str("\x1b[?1049h")?;
+        }
+        self.term.hide_cursor()?;
+        Ok(result)
+    }
+}
+
+impl Drop for Editing {
+    fn drop(&mut self) {
+        self.term.show_cursor().ok();
+        if self.screen {
+            self.term.write_str("\x1b[?1049l").ok();
+        }
+        EDITING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Reads a key, returning `None` if the read was interrupted by Ctrl-C.
+fn read_key(term: &Term) -> io::Result<Option<Key>> {
+    match term.read_key() {
+        Ok(key) => Ok(Some(key)),
+        Err(error) if error.kind() == io::ErrorKind::Interrupted => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn diffstat(repository: &Repository, commit: &Commit, width: usize) -> Result<String, git2::Error> {
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let stats = diff.stats()?.to_buf(DiffStatsFormat::FULL, width)?;
+    Ok(stats.as_str().unwrap_or_default().to_string())
+}
+
+impl Tui {
+    pub fn new(prompt_parents: bool) -> Self {
+        Self {
+            prompt_parents,
+            ..Default::default()
+        }
+    }
+
+    /// The branch the commit at `position` is predicted to conflict on, if any.
+    fn conflict(&self, position: usize) -> Option<usize> {
+        self.conflicts.get(position).copied().flatten()
+    }
+
+    /// Trial-applies the plan again if assignments changed since the last prediction.
+    fn predict(&mut self, plan: &Plan) {
+        let current = assignments(plan);
+        if current == self.predicted {
+            return;
+        }
+        if let Some(predictor) = &self.predictor {
+            self.conflicts = predictor.predict(plan).unwrap_or_else(|error| {
+                debug!("Conflict prediction failed: {}", error);
+                vec![]
+            });
+        }
+        self.predicted = current;
+    }
+
+    fn is_selected(&self, position: usize) -> bool {
+        match &self.selection {
+            Selection::None => position == self.cursor,
+            Selection::Range(anchor) => {
+                (position >= *anchor && position <= self.cursor)
+                    || (position <= *anchor && position >= self.cursor)
+            }
+            Selection::Set(positions) => positions.contains(&position),
+        }
+    }
+
+    /// Changes the branch of every selected commit, remembering the previous assignments for undo.
+    /// Folded commits move along with the commits they're folded into.
+    fn assign(&mut self, plan: &mut Plan, change: impl Fn(usize) -> usize) {
+        let before = assignments(plan);
+        let mut positions = BTreeSet::new();
+        for position in (0..plan.commits.len()).filter(|&p| self.is_selected(p)) {
+            positions.insert(fold_target(plan, position).unwrap_or(position));
+        }
+        for position in positions {
+            let branch_index = &mut plan.commits[position].branch_index;
+            *branch_index = change(*branch_index).min(plan.branches.len() - 1);
+        }
+        for position in 0..plan.commits.len() {
+            if let Some(target) = fold_target(plan, position) {
+                plan.commits[position].branch_index = plan.commits[target].branch_index;
+            }
+        }
+        self.record(plan, before);
+    }
+
+    /// Folds the commit under the cursor into the next earlier one that isn't folded itself, or unfolds it.
+    fn fold(&mut self, plan: &mut Plan, kind: FoldKind) {
+        let before = assignments(plan);
+        let position = self.cursor;
+        if plan.commits[position].fold.map(|fold| fold.kind) == Some(kind) {
+            plan.commits[position].fold = None;
+        } else if let Some(target) =
+            (position + 1..plan.commits.len()).find(|&p| plan.commits[p].fold.is_none())
+        {
+            let into = plan.commits[target].commit.id();
+            plan.commits[position].fold = Some(Fold { kind, into });
+            plan.commits[position].branch_index = plan.commits[target].branch_index;
+        }
+        self.record(plan, before);
+    }
+
+    /// Opens git's editor on the message of the commit under the cursor.
+    fn reword(&mut self, editing: &Editing, plan: &mut Plan) -> Result<(), EditorError> {
+        let before = assignments(plan);
+        let editor = git_editor(plan.repository)?;
+        let path = plan.repository.path().join("BACKPORT_EDITMSG");
+        let commit = &mut plan.commits[self.cursor];
+        editing.suspended(|| reword(&editor, &path, commit))??;
+        self.record(plan, before);
+        Ok(())
+    }
+
+    /// Remembers the assignments before a change for undo, if anything changed.
+    fn record(&mut self, plan: &Plan, before: Assignments) {
+        if assignments(plan) != before {
+            self.undo.push(before);
+            self.redo.clear();
+        }
+    }
+
+    /// Reads a line of input in the footer. Returns `None` if it's aborted with Escape or interrupted.
+    fn read_line(
+        &mut self,
+        term: &Term,
+        plan: &Plan,
+        problems: &[Problem],
+        label: &str,
+    ) -> Result<Option<String>, EditorError> {
+        let mut input = String::new();
+        loop {
+            self.prompt = Some(format!("{}: {}", label, input));
+            self.draw(term, plan, problems)?;
+            let key = read_key(term)?;
+            match key {
+                Some(Key::Char(c)) if !c.is_control() => input.push(c),
+                Some(Key::Backspace) => {
+                    input.pop();
+                }
+                Some(Key::Enter) => break,
+                Some(Key::Escape) | None => {
+                    self.canceled = key.is_none();
+                    self.prompt = None;
+                    return Ok(None);
+                }
+                _ => (),
+            }
+        }
+        self.prompt = None;
+        Ok(Some(input))
+    }
+
+    /// Lets the user assign the hunks of the commit under the cursor to branches and splits it accordingly.
+    /// Splitting moves commits to other positions, so it clears the undo history.
+    fn split(
+        &mut self,
+        term: &Term,
+        plan: &mut Plan,
+        problems: &[Problem],
+    ) -> Result<(), EditorError> {
+        let position = self.cursor;
+        if plan.commits[position].commit.parent_count() != 1 {
+            return Ok(());
+        }
+        let hunks = plan.hunks(position)?;
+        if hunks.is_empty() {
+            return Ok(());
+        }
+        self.splitting = Some(Splitting {
+            position,
+            hunks,
+            cursor: 0,
+            scroll: 0,
+        });
+        loop {
+            self.draw(term, plan, problems)?;
+            let key = read_key(term)?;
+            let splitting = self.splitting.as_mut().unwrap();
+            let last = splitting.hunks.len() - 1;
+            let hunk = &mut splitting.hunks[splitting.cursor];
+            use Key::*;
+            match key {
+                Some(ArrowLeft) | Some(Char('h')) => {
+                    hunk.branch_index = hunk.branch_index.saturating_sub(1)
+                }
+                Some(ArrowRight) | Some(Char('l')) => {
+                    hunk.branch_index = (hunk.branch_index + 1).min(plan.branches.len() - 1)
+                }
+                Some(Char(c)) if c.is_ascii_digit() => {
+                    let target = c.to_digit(10).unwrap() as usize;
+                    if target < plan.branches.len() {
+                        hunk.branch_index = target
+                    }
+                }
+                Some(ArrowUp) | Some(Char('k')) => {
+                    splitting.cursor = splitting.cursor.saturating_sub(1)
+                }
+                Some(ArrowDown) | Some(Char('j')) => {
+                    splitting.cursor = (splitting.cursor + 1).min(last)
+                }
+                Some(Enter) => break,
+                Some(Escape) | None => {
+                    self.canceled = key.is_none();
+                    self.splitting = None;
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+
+        let splitting = self.splitting.take().unwrap();
+        let before = assignments(plan);
+        plan.split(position, &splitting.hunks)?;
+        let added = plan.commits.len() - before.len();
+        if added > 0 {
+            self.original.splice(
+                position + 1..position + 1,
+                vec![self.original[position]; added],
+            );
+            self.selection = Selection::None;
+            self.undo.clear();
+            self.redo.clear();
+        } else {
+            self.record(plan, before);
+        }
+        Ok(())
+    }
+
+    fn preview(&mut self, plan: &Plan, width: usize) -> Result<Vec<String>, git2::Error> {
+        let backport_commit = &plan.commits[self.cursor];
+        let commit = &backport_commit.commit;
+        let mut lines = vec![format!(
+            "Author: {}",
+            commit.author().name().unwrap_or_default()
+        )];
+        lines.push(String::new());
+        // Messages can be reworded, so only the diffstat is cached.
+        let message = match &backport_commit.message {
+            Some(message) => message.into(),
+            None => String::from_utf8_lossy(commit.message_bytes()),
+        };
+        lines.extend(
+            message
+                .trim_end()
+                .lines()
+                .map(|line| format!("    {}", line)),
+        );
+        lines.push(String::new());
+        if let Entry::Vacant(entry) = self.diffstats.entry(commit.id()) {
+            entry.insert(
+                diffstat(plan.repository, commit, width)?
+                    .lines()
+                    .map(str::to_string)
+                    .collect(),
+            );
+        }
+        lines.extend(self.diffstats[&commit.id()].iter().cloned());
+        Ok(lines)
+    }
+
+    fn draw(&mut self, term: &Term, plan: &Plan, problems: &[Problem]) -> Result<(), EditorError> {
+        let (rows, columns) = term.size();
+        let (rows, columns) = (rows as usize, columns as usize);
+        let preview_height = if rows >= 16 { rows / 3 } else { 0 };
+        // Header and footer, plus the preview's title.
+        let chrome = if preview_height > 0 { 3 } else { 2 };
+        let list_height = rows.saturating_sub(chrome + preview_height).max(1);
+
+        // Commits land on the branch in their column and reach the more junior ones to its left through merges.
+        let column_width = (columns / 3 / plan.branches.len()).clamp(2, 16);
+        let mut lines = vec![];
+        lines.push(
+            plan.branches
+                .iter()
+                .map(|branch| {
+                    pad_str(
+                        &branch.name,
+                        column_width - 1,
+                        Alignment::Left,
+                        Some(if column_width > 3 { "…" } else { "" }),
+                    )
+                    .into_owned()
+                        + " "
+                })
+                .collect::<String>(),
+        );
+
+        let preview = if let Some(splitting) = &mut self.splitting {
+            scroll_to(splitting.cursor, &mut splitting.scroll, list_height);
+            for (index, hunk) in splitting
+                .hunks
+                .iter()
+                .enumerate()
+                .skip(splitting.scroll)
+                .take(list_height)
+            {
+                let moved = hunk.branch_index != plan.commits[splitting.position].branch_index;
+                let mut line = markers(plan, column_width, hunk.branch_index, moved) + "   ";
+                let text = format!("{} {}", hunk.path.display(), hunk.header);
+                let text = truncate_str(
+                    &text,
+                    columns.saturating_sub(measure_text_width(&line)),
+                    "…",
+                )
+                .into_owned();
+                line += &if index == splitting.cursor {
+                    style(text).reverse().to_string()
+                } else {
+                    text
+                };
+                lines.push(line);
+            }
+            lines.resize(1 + list_height, String::new());
+
+            let hunk = &splitting.hunks[splitting.cursor];
+            let mut preview = vec![
+                format!(
+                    "── Splitting {} ── hunk {}/{} on {} ",
+                    &plan.commits[splitting.position].commit.id().to_string()[..8],
+                    splitting.cursor + 1,
+                    splitting.hunks.len(),
+                    plan.branches[hunk.branch_index].name
+                ) + &"─".repeat(columns),
+            ];
+            preview.extend(hunk.lines.iter().map(|line| match line.chars().next() {
+                Some('+') => style(line).green().to_string(),
+                Some('-') => style(line).red().to_string(),
+                _ => line.clone(),
+            }));
+            preview
+        } else {
+            scroll_to(self.cursor, &mut self.scroll, list_height);
+            for (position, commit) in plan
+                .commits
+                .iter()
+                .enumerate()
+                .skip(self.scroll)
+                .take(list_height)
+            {
+                let moved = self.original.get(position) != Some(&commit.branch_index);
+                let mut line = markers(plan, column_width, commit.branch_index, moved);
+                line += if self.is_selected(position) && !matches!(self.selection, Selection::None)
+                {
+                    "*"
+                } else {
+                    " "
+                };
+                line += &if problems.iter().any(|p| p.commit == commit.commit.id()) {
+                    style("! ").red().bold().to_string()
+                } else if self.conflict(position).is_some() {
+                    style("✗ ").red().to_string()
+                } else {
+                    "  ".to_string()
+                };
+                let id = commit.commit.id().to_string();
+                let text = format!(
+                    "{}{} {}",
+                    match commit.fold.map(|fold| fold.kind) {
+                        Some(FoldKind::Fixup) => "fixup ",
+                        Some(FoldKind::Squash) => "squash ",
+                        None if commit.message.is_some() => "reword ",
+                        None if commit.duplicate.is_some() => "duplicate ",
+                        None => "",
+                    },
+                    &id[..8],
+                    match &commit.message {
+                        Some(message) => message.lines().next().unwrap_or_default(),
+                        None => commit.commit.summary().unwrap_or_default(),
+                    }
+                );
+                let text = truncate_str(
+                    &text,
+                    columns.saturating_sub(measure_text_width(&line)),
+                    "…",
+                )
+                .into_owned();
+                line += &if position == self.cursor {
+                    style(text).reverse().to_string()
+                } else {
+                    text
+                };
+                lines.push(line);
+            }
+            lines.resize(1 + list_height, String::new());
+
+            let commit = &plan.commits[self.cursor];
+            let mut title = format!(
+                "── {}/{} on {} ",
+                self.cursor + 1,
+                plan.commits.len(),
+                plan.branches[commit.branch_index].name
+            );
+            if !problems.is_empty() {
+                title += &format!("── {} problems ", problems.len());
+            }
+            let conflicts = self.conflicts.iter().filter(|c| c.is_some()).count();
+            if conflicts > 0 {
+                title += &format!("── {} predicted conflicts ", conflicts);
+            }
+            let mut preview = vec![title + &"─".repeat(columns)];
+            for problem in problems.iter().filter(|p| p.commit == commit.commit.id()) {
+                preview.push(
+                    style(format!("Error: {}", problem.message))
+                        .red()
+                        .to_string(),
+                );
+            }
+            if let Some(branch_index) = self.conflict(self.cursor) {
+                preview.push(
+                    style(format!(
+                        "Predicted to conflict on {}",
+                        plan.branches[branch_index].name
+                    ))
+                    .red()
+                    .to_string(),
+                );
+            }
+            if preview_height > 0 {
+                preview.extend(self.preview(plan, columns)?);
+            }
+            preview
+        };
+
+        if preview_height > 0 {
+            // The title, plus as much of the preview as fits.
+            lines.extend(preview.into_iter().take(1 + preview_height));
+            lines.resize(2 + list_height + preview_height, String::new());
+        }
+        lines.push(match &self.prompt {
+            Some(prompt) => prompt.clone(),
+            None if self.splitting.is_some() => style(SPLIT_FOOTER).dim().to_string(),
+            None => style(FOOTER).dim().to_string(),
+        });
+
+        for (y, line) in lines.iter().enumerate().take(rows) {
+            term.move_cursor_to(0, y)?;
+            term.write_str(&pad_str(line, columns, Alignment::Left, Some("")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps `cursor` within the `height` rows shown from `scroll` on.
+fn scroll_to(cursor: usize, scroll: &mut usize, height: usize) {
+    if cursor < *scroll {
+        *scroll = cursor
+    } else if cursor >= *scroll + height {
+        *scroll = cursor + 1 - height
+    }
+}
+
+/// The branch columns of a row for something assigned to `branch_index`.
+fn markers(plan: &Plan, column_width: usize, branch_index: usize, moved: bool) -> String {
+    let mut line = String::new();
+    for column in 0..plan.branches.len() {
+        let marker = if column == branch_index {
+            if moved {
+                style("●").yellow().bold().to_string()
+            } else {
+                style("●").green().to_string()
+            }
+        } else if column < branch_index {
+            style("│").dim().to_string()
+        } else {
+            " ".to_string()
+        };
+        line += &pad_str(&marker, column_width, Alignment::Left, None);
+    }
+    line
+}
+
+/// Each commit's branch, fold and new message.
+type Assignments = Vec<(usize, Option<Fold>, Option<String>)>;
+
+fn assignments(plan: &Plan) -> Assignments {
+    plan.commits
+        .iter()
+        .map(|c| (c.branch_index, c.fold, c.message.clone()))
+        .collect()
+}
+
+/// The position of the commit the one at `position` is folded into, if any.
+fn fold_target(plan: &Plan, position: usize) -> Option<usize> {
+    plan.position(&plan.commits[position].fold?.into.to_string())
+}
+
+fn restore(plan: &mut Plan, assignments: &[(usize, Option<Fold>, Option<String>)]) {
+    for (commit, (branch_index, fold, message)) in plan.commits.iter_mut().zip(assignments) {
+        commit.branch_index = *branch_index;
+        commit.fold = *fold;
+        commit.message = message.clone();
+    }
+}
+
+impl Editor for Tui {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        if self.canceled {
+            return Ok(EditOutcome::Cancel);
+        }
+        if plan.commits.is_empty() {
+            // Nothing to assign, e.g. because the branches are equal.
+            return Ok(EditOutcome::Apply);
+        }
+        if self.original.is_empty() {
+            self.original = plan.commits.iter().map(|c| c.branch_index).collect();
+        }
+        let term = Term::stdout();
+        if self.predictor.is_none() {
+            self.predictor = ConflictPredictor::new(plan.repository)
+                .map_err(|error| debug!("Conflict prediction unavailable: {}", error))
+                .ok();
+        }
+        let editing = Editing::start(&term, true)?;
+        loop {
+            self.predict(plan);
+            self.draw(&term, plan, problems)?;
+            let page = (term.size().0 as usize / 3).max(1);
+            let last = plan.commits.len() - 1;
+            use Key::*;
+            let key = match read_key(&term)? {
+                Some(key) => key,
+                None => return Ok(EditOutcome::Cancel),
+            };
+            match key {
+                ArrowLeft | Char('h') => self.assign(plan, |b| b.saturating_sub(1)),
+                ArrowRight | Char('l') => self.assign(plan, |b| b + 1),
+                Char(c) if c.is_ascii_digit() => {
+                    let target = c.to_digit(10).unwrap() as usize;
+                    if target < plan.branches.len() {
+                        self.assign(plan, |_| target)
+                    }
+                }
+                Char('v') => {
+                    self.selection = match self.selection {
+                        Selection::Range(_) => Selection::None,
+                        _ => Selection::Range(self.cursor),
+                    }
+                }
+                Char('b') => self.selection = Selection::Set((self.cursor..=last).collect()),
+                Char('/') => {
+                    if let Some(path) =
+                        self.read_line(&term, plan, problems, "Select commits touching path")?
+                    {
+                        let matcher = Matcher::Path(path);
+                        let mut positions = BTreeSet::new();
+                        for (position, commit) in plan.commits.iter().enumerate() {
+                            if matcher.matches(plan.repository, &commit.commit)? {
+                                positions.insert(position);
+                            }
+                        }
+                        self.selection = Selection::Set(positions);
+                    } else if self.canceled {
+                        return Ok(EditOutcome::Cancel);
+                    }
+                }
+                Char('s') => {
+                    self.split(&term, plan, problems)?;
+                    if self.canceled {
+                        return Ok(EditOutcome::Cancel);
+                    }
+                }
+                Char('f') => self.fold(plan, FoldKind::Fixup),
+                Char('F') => self.fold(plan, FoldKind::Squash),
+                Char('r') => self.reword(&editing, plan)?,
+                Char('u') => {
+                    if let Some(previous) = self.undo.pop() {
+                        self.redo.push(assignments(plan));
+                        restore(plan, &previous);
+                    }
+                }
+                Char('\u{12}') => {
+                    if let Some(next) = self.redo.pop() {
+                        self.undo.push(assignments(plan));
+                        restore(plan, &next);
+                    }
+                }
+                Escape if !matches!(self.selection, Selection::None) => {
+                    self.selection = Selection::None
+                }
+                ArrowUp | Char('k') => self.cursor = self.cursor.saturating_sub(1),
+                ArrowDown | Char('j') => self.cursor = (self.cursor + 1).min(last),
+                Char('\u{15}') => self.cursor = self.cursor.saturating_sub(page),
+                Char('\u{4}') => self.cursor = (self.cursor + page).min(last),
+                Char('g') => self.cursor = 0,
+                Char('G') => self.cursor = last,
+                Enter => return Ok(EditOutcome::Apply),
+                Escape | Char('q') => return Ok(EditOutcome::Cancel),
+                _ => (),
+            }
+        }
+    }
+
+    fn choose_parent(
+        &mut self,
+        commit: &Commit,
+        candidates: &[ParentCandidate],
+    ) -> Result<Option<usize>, EditorError> {
+        if !self.prompt_parents || self.canceled {
+            return Ok(None);
+        }
+        let out = Term::stderr();
+        let _editing = Editing::start(&out, false)?;
+        out.write_line(&format!(
+            "{} reaches the next branch through several parents. Follow which? (Enter for default)",
+            commit.id(),
+        ))?;
+        for (i, candidate) in candidates.iter().enumerate() {
+            out.write_line(&format!(
+                "{}: {} ({} commits) {}",
+                i + 1,
+                truncate_str(&candidate.commit.id().to_string(), 8, ""),
+                candidate.distance,
+                candidate.commit.summary().unwrap_or_default(),
+            ))?;
+        }
+        loop {
+            match read_key(&out)? {
+                None => {
+                    self.canceled = true;
+                    break Ok(None);
+                }
+                Some(Key::Char(c)) => match c.to_digit(10) {
+                    Some(i) if (1..=candidates.len()).contains(&(i as usize)) => {
+                        break Ok(Some(i as usize - 1))
+                    }
+                    _ => (),
+                },
+                Some(Key::Enter) => break Ok(None),
+                _ => (),
+            }
+        }
+    }
+}
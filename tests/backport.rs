@@ -0,0 +1,785 @@
+mod common;
+
+use {
+    common::{Moves, TestRepo},
+    git2::{BranchType, Oid, Signature, Time},
+    git_backport::{
+        backport, discover_ancestors,
+        editor::{EditOutcome, Editor, EditorError, Plan, Problem},
+        BackportArgs, EmptyCommits, Error, MergeStrategy, Tip,
+    },
+};
+
+/// `release`: base, r1; `develop`: d1, d2; `feature`: f1, f2.
+fn stack() -> TestRepo {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base", "r1"]);
+    let develop = repo.chain(Some(release), &["d1", "d2"]);
+    let feature = repo.chain(Some(develop), &["f1", "f2"]);
+    repo.branch("release", release);
+    repo.branch("develop", develop);
+    repo.branch("feature", feature);
+    repo
+}
+
+const STACK: &[&str] = &["feature", "develop", "release"];
+
+#[test]
+fn unchanged_plan_keeps_history() {
+    let repo = stack();
+    let before = STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>();
+
+    let heads = repo.backport(STACK, &[]).unwrap();
+
+    assert_eq!(heads, before);
+    assert_eq!(
+        STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>(),
+        before
+    );
+}
+
+#[test]
+fn move_oldest_commit_fast_forwards() {
+    let repo = stack();
+    let feature = repo.tip("feature");
+
+    repo.backport(STACK, &[("f1", 1)]).unwrap();
+
+    assert_eq!(repo.log(repo.tip("develop"), None).last().unwrap(), "f1");
+    assert_eq!(repo.tip("feature"), feature);
+}
+
+#[test]
+fn move_commit_to_most_senior_branch() {
+    let repo = stack();
+    let feature_files = repo.files(repo.tip("feature"));
+    let develop_files = repo.files(repo.tip("develop"));
+
+    repo.backport(STACK, &[("f2", 2)]).unwrap();
+
+    let (release, develop, feature) = (
+        repo.tip("release"),
+        repo.tip("develop"),
+        repo.tip("feature"),
+    );
+    assert_eq!(repo.log(release, None), ["base", "r1", "f2"]);
+    assert!(repo.files(release).contains_key("f2.txt"));
+    assert!(repo.is_ancestor(release, develop));
+    assert!(repo.is_ancestor(develop, feature));
+
+    let mut expected_develop_files = develop_files;
+    expected_develop_files.insert("f2.txt".to_string(), "f2".to_string());
+    assert_eq!(repo.files(develop), expected_develop_files);
+    assert_eq!(repo.files(feature), feature_files);
+
+    // Catching up creates merges rather than rebasing the more junior branches.
+    assert_eq!(
+        repo.find(develop).summary(),
+        Some("Merge release into develop")
+    );
+    assert_eq!(repo.find(develop).parent_count(), 2);
+}
+
+#[test]
+fn moves_keep_commit_order_per_branch() {
+    let repo = stack();
+    let feature_files = repo.files(repo.tip("feature"));
+
+    repo.backport(STACK, &[("f1", 2), ("d2", 2)]).unwrap();
+
+    let release = repo.tip("release");
+    assert_eq!(repo.log(release, None), ["base", "r1", "d2", "f1"]);
+    assert_eq!(repo.files(repo.tip("feature")), feature_files);
+    assert_eq!(
+        repo.log(repo.tip("develop"), Some(release))
+            .iter()
+            .filter(|s| !s.starts_with("Merge"))
+            .collect::<Vec<_>>(),
+        ["d1"]
+    );
+}
+
+#[test]
+fn loop_on_feature_survives_moves() {
+    // feature: f1 -> (f2 | s1) -> m
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let f1 = repo.chain(Some(release), &["f1"]);
+    let f2 = repo.chain(Some(f1), &["f2"]);
+    let s1 = repo.chain(Some(f1), &["s1"]);
+    let merge = repo.merge(f2, s1, "m");
+    repo.branch("release", release);
+    repo.branch("feature", merge);
+    let feature_files = repo.files(merge);
+
+    repo.backport(&["feature", "release"], &[("f1", 1)])
+        .unwrap();
+
+    let release = repo.tip("release");
+    let feature = repo.tip("feature");
+    assert_eq!(repo.log(release, None), ["base", "f1"]);
+    assert_eq!(repo.files(feature), feature_files);
+    assert_eq!(repo.tip("feature"), merge, "f1 was only fast-forwarded");
+
+    // Moving the tip of the side chain's base changes nothing below the loop.
+    repo.backport(&["feature", "release"], &[("f2", 1)])
+        .unwrap();
+    let release = repo.tip("release");
+    let feature = repo.tip("feature");
+    assert_eq!(repo.log(release, None), ["base", "f1", "f2"]);
+    assert_eq!(repo.files(feature), feature_files);
+    assert!(repo.is_ancestor(release, feature));
+}
+
+#[test]
+fn side_chain_forked_below_moved_commit_is_kept() {
+    // feature: f0 -> f1 -> m, with s1 forked from f0 and merged by m.
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let f0 = repo.chain(Some(release), &["f0"]);
+    let f1 = repo.chain(Some(f0), &["f1"]);
+    let s1 = repo.chain(Some(f0), &["s1"]);
+    let merge = repo.merge(f1, s1, "m");
+    repo.branch("release", release);
+    repo.branch("feature", merge);
+    let feature_files = repo.files(merge);
+
+    repo.backport(&["feature", "release"], &[("f1", 1)])
+        .unwrap();
+
+    let release = repo.tip("release");
+    let feature = repo.tip("feature");
+    assert_eq!(repo.log(release, None), ["base", "f1"]);
+    assert_eq!(repo.files(feature), feature_files);
+    assert!(repo.is_ancestor(release, feature));
+    assert!(repo.is_ancestor(s1, feature));
+}
+
+#[test]
+fn side_chain_forked_from_moved_commit_is_rebased() {
+    // feature: f0 -> f1 -> f2 -> m, with s1 forked from f1 and merged by m.
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let f1 = repo.chain(Some(release), &["f0", "f1"]);
+    let f2 = repo.chain(Some(f1), &["f2"]);
+    let s1 = repo.chain(Some(f1), &["s1"]);
+    let merge = repo.merge(f2, s1, "m");
+    repo.branch("release", release);
+    repo.branch("feature", merge);
+    let feature_files = repo.files(merge);
+
+    repo.backport(&["feature", "release"], &[("f1", 1)])
+        .unwrap();
+
+    let release = repo.tip("release");
+    let feature = repo.tip("feature");
+    assert_eq!(repo.log(release, None), ["base", "f1"]);
+    assert_eq!(repo.files(feature), feature_files);
+    assert!(repo.is_ancestor(release, feature));
+    assert!(
+        !repo.is_ancestor(f1, feature),
+        "The original f1 must not be reachable through s1 anymore"
+    );
+    assert_eq!(
+        repo.log(feature, Some(release))
+            .iter()
+            .filter(|s| *s == "s1")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn release_merged_back_into_feature() {
+    // feature: f1 -> m, where m merges r2 from release.
+    let repo = TestRepo::new();
+    let r1 = repo.chain(None, &["base", "r1"]);
+    let f1 = repo.chain(Some(r1), &["f1"]);
+    let r2 = repo.chain(Some(r1), &["r2"]);
+    let merge = repo.merge(f1, r2, "Merge release into feature");
+    repo.branch("release", r2);
+    repo.branch("feature", merge);
+    let feature_files = repo.files(merge);
+
+    repo.backport(&["feature", "release"], &[]).unwrap();
+    assert_eq!(repo.tip("feature"), merge);
+    assert_eq!(repo.tip("release"), r2);
+
+    repo.backport(
+        &["feature", "release"],
+        &[("Merge release into feature", 1)],
+    )
+    .unwrap();
+    let release = repo.tip("release");
+    assert_eq!(repo.files(repo.tip("feature")), feature_files);
+    assert!(repo.files(release).contains_key("f1.txt"));
+    assert!(repo.is_ancestor(release, repo.tip("feature")));
+}
+
+#[test]
+fn detached_head_returns_new_ids() {
+    let repo = stack();
+    let feature = repo.tip("feature");
+    let head = Tip::resolve_head(&repo.repository, &feature.to_string()).unwrap();
+    assert_eq!(head.branch, None);
+    let tips = vec![
+        head,
+        Tip::resolve_branch(&repo.repository, "refs/heads/develop").unwrap(),
+    ];
+
+    let heads = repo.backport_tips(&tips, &[("f1", 1)]).unwrap();
+
+    assert_eq!(heads[0], feature);
+    assert_eq!(heads[1], repo.tip("develop"));
+    assert_eq!(repo.tip("feature"), feature);
+}
+
+#[test]
+fn detached_base_allows_forward_ports() {
+    let repo = stack();
+    let develop_files = repo.files(repo.tip("develop"));
+    let tips = vec![
+        Tip::resolve_branch(&repo.repository, "develop").unwrap(),
+        Tip::resolve_branch(&repo.repository, "release").unwrap(),
+        Tip::resolve_detached(&repo.repository, "release~1").unwrap(),
+    ];
+    let base = tips[2].commit.id();
+
+    let heads = repo.backport_tips(&tips, &[("r1", 0)]).unwrap();
+
+    assert_eq!(heads[2], base);
+    assert_eq!(repo.tip("release"), base);
+    assert_eq!(
+        repo.log(repo.tip("develop"), None),
+        ["base", "r1", "d1", "d2"]
+    );
+    assert_eq!(repo.files(repo.tip("develop")), develop_files);
+}
+
+#[test]
+fn unordered_chain_is_rejected() {
+    let repo = stack();
+    let before = repo.tip("develop");
+
+    match repo.backport(&["feature", "release", "develop"], &[]) {
+        Err(Error::UnorderedChain {
+            descendant,
+            ancestor,
+        }) => {
+            assert_eq!(descendant, "release");
+            assert_eq!(ancestor, "develop");
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert_eq!(repo.tip("develop"), before);
+}
+
+#[test]
+fn ancestors_are_discovered_in_order() {
+    let repo = stack();
+    repo.branch("unrelated", repo.commit(None, "unrelated", &[]));
+    let head = Tip::resolve_head(&repo.repository, "feature").unwrap();
+
+    let names = |patterns: &[String]| {
+        discover_ancestors(&repo.repository, &head, patterns)
+            .unwrap()
+            .into_iter()
+            .map(|tip| tip.name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&[]), ["develop", "release"]);
+    assert_eq!(names(&["rel*".to_string()]), ["release"]);
+}
+
+#[test]
+fn ambiguous_ancestry_follows_first_parent() {
+    // Both parents of m reach release.
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let a = repo.chain(Some(release), &["a"]);
+    let b = repo.chain(Some(release), &["b"]);
+    let merge = repo.merge(a, b, "m");
+    repo.branch("release", release);
+    repo.branch("feature", merge);
+
+    repo.backport(&["feature", "release"], &[("a", 1)]).unwrap();
+
+    assert_eq!(repo.log(repo.tip("release"), None), ["base", "a"]);
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(merge));
+}
+
+#[test]
+fn conflicting_commits_are_reported() {
+    // f2 changes what f1 wrote, so it doesn't apply to release without f1.
+    let repo = TestRepo::new();
+    let release = repo.commit(None, "base", &[("file", "a\n")]);
+    let f1 = repo.commit(Some(release), "f1", &[("file", "b\n")]);
+    let f2 = repo.commit(Some(f1), "f2", &[("file", "c\n")]);
+    repo.branch("release", release);
+    repo.branch("feature", f2);
+
+    match repo.backport(&["feature", "release"], &[("f2", 1)]) {
+        Err(Error::Conflict { from, into }) => {
+            assert_eq!(from, f2.to_string());
+            assert_eq!(into, "release");
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert_eq!(repo.tip("release"), release);
+    assert_eq!(repo.tip("feature"), f2);
+}
+
+#[test]
+fn verification_rejects_changed_trees() {
+    // f2 conflicts with f1 when moved past it, and `ours` silently drops the difference.
+    let repo = TestRepo::new();
+    let release = repo.commit(None, "base", &[("file", "a\n")]);
+    let f1 = repo.commit(Some(release), "f1", &[("file", "b\n")]);
+    let f2 = repo.commit(Some(f1), "f2", &[("file", "c\n")]);
+    repo.branch("release", release);
+    repo.branch("feature", f2);
+    let tips = repo.tips(&["feature", "release"]);
+
+    let result = backport(BackportArgs {
+        backup: true,
+        merge_strategy: MergeStrategy::Ours,
+        ..repo.args(&tips, Moves(&[("f2", 1)]))
+    });
+
+    match result {
+        Err(Error::VerificationFailed { branch, .. }) => assert_eq!(branch, "feature"),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert_eq!(repo.tip("feature"), f2);
+    assert_eq!(repo.tip("release"), release);
+    assert_eq!(backups(&repo), Vec::<String>::new());
+}
+
+/// Names of the backup branches.
+fn backups(repo: &TestRepo) -> Vec<String> {
+    let mut names = repo
+        .repository
+        .branches(Some(BranchType::Local))
+        .unwrap()
+        .map(|branch| branch.unwrap().0.name().unwrap().unwrap().to_string())
+        .filter(|name| name.starts_with("git-backport-backup/"))
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn backups_keep_the_old_tips() {
+    let repo = stack();
+    let before = STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>();
+    let tips = repo.tips(STACK);
+
+    backport(BackportArgs {
+        backup: true,
+        ..repo.args(&tips, Moves(&[("f2", 2)]))
+    })
+    .unwrap();
+
+    assert_eq!(
+        backups(&repo),
+        [
+            "git-backport-backup/develop",
+            "git-backport-backup/feature",
+            "git-backport-backup/release"
+        ]
+    );
+    for (name, old) in STACK.iter().zip(before) {
+        assert_eq!(repo.tip(&format!("git-backport-backup/{}", name)), old);
+    }
+}
+
+fn cherry_pick<E: Editor>(
+    repo: &TestRepo,
+    branches: &[&str],
+    empty: EmptyCommits,
+    editor: E,
+) -> Result<Vec<Oid>, Error> {
+    let tips = repo.tips(branches);
+    backport(BackportArgs {
+        cherry_pick: true,
+        empty,
+        ..repo.args(&tips, editor)
+    })
+}
+
+#[test]
+fn cherry_picks_keep_history() {
+    let repo = stack();
+    let before = STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>();
+    let feature_files = repo.files(before[0]);
+
+    cherry_pick(&repo, STACK, Default::default(), Moves(&[("f1", 2)])).unwrap();
+
+    let release = repo.find(repo.tip("release"));
+    assert_eq!(release.summary(), Some("f1"));
+    assert_eq!(release.parent_ids().collect::<Vec<_>>(), [before[2]]);
+    for (index, name) in ["feature", "develop"].iter().enumerate() {
+        let tip = repo.find(repo.tip(name));
+        assert_eq!(
+            tip.parent_ids().collect::<Vec<_>>(),
+            [before[index], repo.tip(STACK[index + 1])]
+        );
+    }
+    assert_eq!(repo.files(repo.tip("feature")), feature_files);
+}
+
+/// Moves `d1` to `feature`, then cancels, recording the problems.
+struct ForwardPort(Vec<Vec<Problem>>);
+
+impl Editor for ForwardPort {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.0.push(problems.to_vec());
+        if !problems.is_empty() {
+            return Ok(EditOutcome::Cancel);
+        }
+        for commit in &mut plan.commits {
+            if commit.commit.summary() == Some("d1") {
+                commit.branch_index = 0;
+            }
+        }
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn cherry_picks_reject_forward_ports() {
+    let repo = stack();
+    let mut editor = ForwardPort(vec![]);
+
+    assert!(matches!(
+        cherry_pick(&repo, STACK, Default::default(), &mut editor),
+        Err(Error::Canceled)
+    ));
+
+    assert_eq!(editor.0.len(), 2);
+    assert_eq!(editor.0[1].len(), 1);
+    assert!(editor.0[1][0]
+        .message
+        .ends_with("without rewriting history"));
+}
+
+#[test]
+fn cherry_picks_reject_rewriting_refs() {
+    let repo = stack();
+    let before = STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>();
+    let tips = repo.tips(STACK);
+
+    for (update_refs, update_tags) in [(true, false), (false, true)] {
+        let result = backport(BackportArgs {
+            cherry_pick: true,
+            update_refs,
+            update_tags,
+            ..repo.args(&tips, Moves(&[("f1", 2)]))
+        });
+        assert!(matches!(result, Err(Error::RequiresRewrite(_))));
+    }
+    assert_eq!(
+        STACK.iter().map(|b| repo.tip(b)).collect::<Vec<_>>(),
+        before
+    );
+}
+
+/// Records the duplicates the plan's commits were found to be of, then cancels.
+struct Duplicates(Vec<Option<Oid>>);
+
+impl Editor for Duplicates {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.0 = plan.commits.iter().map(|c| c.duplicate).collect();
+        Ok(EditOutcome::Cancel)
+    }
+}
+
+#[test]
+fn duplicates_are_found_despite_clock_skew() {
+    // `fix` claims to be committed long before `again` was authored.
+    let repo = TestRepo::new();
+    let base = repo.commit(None, "base", &[("file", "a\n")]);
+    let fix = repo.commit(Some(base), "fix", &[("file", "b\n")]);
+    let skewed = Signature::new("Test", "test@example.com", &Time::new(1000, 0)).unwrap();
+    let release = repo
+        .find(fix)
+        .amend(None, Some(&skewed), Some(&skewed), None, None, None)
+        .unwrap();
+    let revert = repo.commit(Some(release), "revert", &[("file", "a\n")]);
+    let develop = repo.commit(Some(revert), "again", &[("file", "b\n")]);
+    repo.branch("release", release);
+    repo.branch("develop", develop);
+    let tips = repo.tips(&["develop", "release"]);
+    let mut editor = Duplicates(vec![]);
+
+    assert!(matches!(
+        backport(repo.args(&tips, &mut editor)),
+        Err(Error::Canceled)
+    ));
+
+    // `again`, then `revert`.
+    assert_eq!(editor.0, [Some(release), None]);
+}
+
+#[test]
+fn duplicates_are_dropped_where_already_applied() {
+    // `again` reapplies `fix` under another message after it was reverted, so it only duplicates it on `release`.
+    let repo = TestRepo::new();
+    let base = repo.commit(None, "base", &[("file", "a\n")]);
+    let release = repo.commit(Some(base), "fix", &[("file", "b\n")]);
+    let revert = repo.commit(Some(release), "revert", &[("file", "a\n")]);
+    let again = repo.commit(Some(revert), "again", &[("file", "b\n")]);
+    let develop = repo.commit(Some(again), "other", &[("other", "c\n")]);
+    repo.branch("release", release);
+    repo.branch("develop", develop);
+    let develop_files = repo.files(develop);
+
+    cherry_pick(
+        &repo,
+        &["develop", "release"],
+        Default::default(),
+        Moves(&[("again", 1), ("other", 1)]),
+    )
+    .unwrap();
+
+    let new_release = repo.tip("release");
+    assert_eq!(repo.log(new_release, Some(release)), ["other"]);
+    assert_eq!(
+        repo.find(new_release).parent_ids().collect::<Vec<_>>(),
+        [release]
+    );
+    assert_eq!(repo.files(new_release), develop_files);
+    let new_develop = repo.find(repo.tip("develop"));
+    assert_eq!(
+        new_develop.parent_ids().collect::<Vec<_>>(),
+        [develop, new_release]
+    );
+    assert_eq!(repo.files(new_develop.id()), develop_files);
+}
+
+#[test]
+fn empty_commits_are_dropped_kept_or_stopped() {
+    for &empty in &[EmptyCommits::Drop, EmptyCommits::Keep, EmptyCommits::Stop] {
+        // `again` only restores what `release` already has, unlike `fix`, which also changes `other`.
+        let repo = TestRepo::new();
+        let base = repo.commit(None, "base", &[("file", "a\n")]);
+        let release = repo.commit(Some(base), "fix", &[("file", "b\n"), ("other", "b\n")]);
+        let revert = repo.commit(Some(release), "revert", &[("file", "a\n")]);
+        let develop = repo.commit(Some(revert), "again", &[("file", "b\n")]);
+        repo.branch("release", release);
+        repo.branch("develop", develop);
+
+        let result = cherry_pick(
+            &repo,
+            &["develop", "release"],
+            empty,
+            Moves(&[("again", 1)]),
+        );
+
+        let tip = repo.tip("release");
+        match empty {
+            EmptyCommits::Drop => assert_eq!(tip, release),
+            EmptyCommits::Keep => {
+                assert_eq!(repo.log(tip, None), ["base", "fix", "again"]);
+                assert_eq!(repo.find(tip).tree_id(), repo.find(release).tree_id());
+            }
+            EmptyCommits::Stop => {
+                assert!(matches!(result, Err(Error::Empty { .. })));
+                assert_eq!(tip, release);
+                assert_eq!(repo.tip("develop"), develop);
+            }
+        }
+    }
+}
+
+fn backport_with_siblings(
+    repo: &TestRepo,
+    siblings: &[&str],
+    update_refs: bool,
+    moves: &[(&str, usize)],
+) -> Result<Vec<Oid>, Error> {
+    let (tips, siblings) = (repo.tips(STACK), repo.tips(siblings));
+    backport(BackportArgs {
+        siblings: &siblings,
+        update_refs,
+        ..repo.args(&tips, Moves(moves))
+    })
+}
+
+#[test]
+fn siblings_are_rebased_onto_their_branch() {
+    let repo = stack();
+    let sibling = repo.chain(Some(repo.tip("develop")), &["g1", "g2"]);
+    repo.branch("sibling", sibling);
+
+    let heads = backport_with_siblings(&repo, &["sibling"], false, &[("f1", 1)]).unwrap();
+
+    assert_eq!(heads[3], repo.tip("sibling"));
+    assert_eq!(
+        repo.log(repo.tip("sibling"), None),
+        ["base", "r1", "d1", "d2", "f1", "g1", "g2"]
+    );
+    assert_eq!(
+        repo.log(repo.tip("feature"), Some(repo.tip("sibling"))),
+        ["f2"]
+    );
+}
+
+#[test]
+fn siblings_forked_below_a_tip_are_caught_up() {
+    let repo = stack();
+    let d1 = repo.find(repo.tip("develop")).parent_id(0).unwrap();
+    let sibling = repo.chain(Some(d1), &["g1"]);
+    repo.branch("sibling", sibling);
+
+    backport_with_siblings(&repo, &["sibling"], false, &[("d2", 2)]).unwrap();
+
+    let sibling = repo.tip("sibling");
+    assert!(repo.is_ancestor(d1, sibling));
+    assert!(repo.is_ancestor(repo.tip("release"), sibling));
+    let mut files = repo.files(repo.tip("develop"));
+    files.insert("g1.txt".to_string(), "g1".to_string());
+    assert_eq!(repo.files(sibling), files);
+}
+
+#[test]
+fn sibling_conflicts_are_reported() {
+    // `g1` adds `file` like `d2` and `f1`: Forked from `d1`, it conflicts when caught up with `release` gaining `d2`.
+    // Forked from `develop`, it conflicts when rebased onto it gaining `f1`.
+    for (fork, moves) in [("d1", ("d2", 2)), ("develop", ("f1", 1))] {
+        let repo = TestRepo::new();
+        let release = repo.chain(None, &["base", "r1"]);
+        let d1 = repo.chain(Some(release), &["d1"]);
+        let develop = repo.commit(Some(d1), "d2", &[("file", "d2\n")]);
+        let f1 = repo.commit(Some(develop), "f1", &[("file", "f1\n")]);
+        let feature = repo.chain(Some(f1), &["f2"]);
+        let base = if fork == "d1" { d1 } else { develop };
+        let sibling = repo.commit(Some(base), "g1", &[("file", "g1\n")]);
+        for (name, tip) in [
+            ("release", release),
+            ("develop", develop),
+            ("feature", feature),
+            ("sibling", sibling),
+        ] {
+            repo.branch(name, tip);
+        }
+
+        match backport_with_siblings(&repo, &["sibling"], false, &[moves]) {
+            Err(Error::Conflict { from, into }) => {
+                let expected = if fork == "d1" {
+                    "release".to_string()
+                } else {
+                    sibling.to_string()
+                };
+                assert_eq!(from, expected);
+                assert_eq!(into, "sibling");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(repo.tip("release"), release);
+        assert_eq!(repo.tip("develop"), develop);
+        assert_eq!(repo.tip("feature"), feature);
+        assert_eq!(repo.tip("sibling"), sibling);
+    }
+}
+
+#[test]
+fn stacked_siblings_follow_their_base() {
+    // `upper` is stacked on `lower`, which is forked below `develop`'s tip, and listed first.
+    for cherry_pick in [false, true] {
+        let repo = stack();
+        let d1 = repo.find(repo.tip("develop")).parent_id(0).unwrap();
+        let lower = repo.chain(Some(d1), &["g1"]);
+        let upper = repo.chain(Some(lower), &["h1"]);
+        repo.branch("lower", lower);
+        repo.branch("upper", upper);
+
+        let (tips, siblings) = (repo.tips(STACK), repo.tips(&["upper", "lower"]));
+        let heads = backport(BackportArgs {
+            siblings: &siblings,
+            cherry_pick,
+            ..repo.args(&tips, Moves(&[("d2", 2)]))
+        })
+        .unwrap();
+
+        let (new_lower, new_upper) = (repo.tip("lower"), repo.tip("upper"));
+        assert_eq!(heads[3..], [new_upper, new_lower]);
+        assert_ne!(new_lower, lower);
+        assert!(repo.is_ancestor(repo.tip("release"), new_lower));
+        assert!(repo.is_ancestor(new_lower, new_upper));
+        assert!(repo.files(new_upper).contains_key("h1.txt"));
+    }
+}
+
+#[test]
+fn dependent_branches_are_updated_on_request() {
+    for &update_refs in &[false, true] {
+        let repo = stack();
+        let feature = repo.tip("feature");
+        let f1 = repo.find(feature).parent_id(0).unwrap();
+        let dependent = repo.chain(Some(feature), &["h1"]);
+        repo.branch("dependent", dependent);
+        repo.branch("at-f1", f1);
+
+        let heads = backport_with_siblings(&repo, &[], update_refs, &[("d2", 2)]).unwrap();
+
+        assert_eq!(heads.len(), STACK.len());
+        if update_refs {
+            let (feature, dependent) = (repo.tip("feature"), repo.tip("dependent"));
+            assert_eq!(repo.log(dependent, Some(feature)), ["h1"]);
+            assert_eq!(repo.find(dependent).parent_id(0).unwrap(), feature);
+            assert_eq!(repo.tip("at-f1"), repo.find(feature).parent_id(0).unwrap());
+        } else {
+            assert_eq!(repo.tip("dependent"), dependent);
+            assert_eq!(repo.tip("at-f1"), f1);
+        }
+    }
+}
+
+#[test]
+fn tags_are_moved_on_request() {
+    for &update_tags in &[false, true] {
+        let repo = stack();
+        let f1 = repo.find(repo.tip("feature")).parent_id(0).unwrap();
+        let tagger = Signature::new("Tagger", "tagger@example.com", &Time::new(1000, 0)).unwrap();
+        let object = repo.repository.find_object(f1, None).unwrap();
+        repo.repository
+            .tag_lightweight("lightweight", &object, false)
+            .unwrap();
+        repo.repository
+            .tag("annotated", &object, &tagger, "Release f1\n", false)
+            .unwrap();
+
+        let tips = repo.tips(STACK);
+        backport(BackportArgs {
+            update_tags,
+            ..repo.args(&tips, Moves(&[("d2", 2)]))
+        })
+        .unwrap();
+
+        let expected = if update_tags {
+            let rewritten = repo.find(repo.tip("feature")).parent_id(0).unwrap();
+            assert_ne!(rewritten, f1);
+            rewritten
+        } else {
+            f1
+        };
+        let peel = |name: &str| {
+            repo.repository
+                .revparse_single(&format!("refs/tags/{}^{{commit}}", name))
+                .unwrap()
+                .id()
+        };
+        assert_eq!(peel("lightweight"), expected);
+        assert_eq!(peel("annotated"), expected);
+        let tag = repo
+            .repository
+            .revparse_single("refs/tags/annotated")
+            .unwrap()
+            .into_tag()
+            .unwrap();
+        assert_eq!(tag.message(), Some("Release f1\n"));
+        assert_eq!(tag.tagger().unwrap().name(), Some("Tagger"));
+        assert_eq!(tag.tagger().unwrap().when().seconds(), 1000);
+    }
+}
@@ -0,0 +1,206 @@
+//! Builds throwaway repositories with scripted topologies.
+
+#![allow(dead_code)]
+
+use {
+    git2::{Commit, MergeOptions, Oid, Repository, Signature, Sort},
+    git_backport::{
+        backport,
+        editor::{EditOutcome, Editor, EditorError, Plan, Problem},
+        BackportArgs, Error, Tip,
+    },
+    std::{collections::BTreeMap, path::Path},
+    tempfile::TempDir,
+};
+
+pub struct TestRepo {
+    pub repository: Repository,
+    _directory: TempDir,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let directory = TempDir::new().unwrap();
+        let repository = Repository::init(directory.path()).unwrap();
+        {
+            let mut config = repository.config().unwrap();
+            config.set_str("user.name", "Test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        Self {
+            repository,
+            _directory: directory,
+        }
+    }
+
+    pub fn find(&self, id: Oid) -> Commit<'_> {
+        self.repository.find_commit(id).unwrap()
+    }
+
+    /// Creates a commit on top of `parent` that writes `files` (path, content).
+    pub fn commit(&self, parent: Option<Oid>, message: &str, files: &[(&str, &str)]) -> Oid {
+        let parents = parent.map(|p| self.find(p)).into_iter().collect::<Vec<_>>();
+        let base = parents.first().map(|p| p.tree().unwrap());
+        self.commit_tree(base.as_ref(), message, files, &parents)
+    }
+
+    /// Adds one commit per name, each writing `<name>.txt`, and returns the last one.
+    pub fn chain(&self, mut parent: Option<Oid>, names: &[&str]) -> Oid {
+        for name in names {
+            let path = format!("{}.txt", name);
+            parent = Some(self.commit(parent, name, &[(&path, name)]));
+        }
+        parent.unwrap()
+    }
+
+    /// Merges `theirs` into `ours`, failing on conflicts.
+    pub fn merge(&self, ours: Oid, theirs: Oid, message: &str) -> Oid {
+        let parents = [self.find(ours), self.find(theirs)];
+        let mut index = self
+            .repository
+            .merge_commits(
+                &parents[0],
+                &parents[1],
+                Some(MergeOptions::new().fail_on_conflict(true)),
+            )
+            .unwrap();
+        let tree = self
+            .repository
+            .find_tree(index.write_tree_to(&self.repository).unwrap())
+            .unwrap();
+        self.commit_tree(Some(&tree), message, &[], &parents)
+    }
+
+    fn commit_tree(
+        &self,
+        base: Option<&git2::Tree>,
+        message: &str,
+        files: &[(&str, &str)],
+        parents: &[Commit],
+    ) -> Oid {
+        let mut builder = self.repository.treebuilder(base).unwrap();
+        for (path, content) in files {
+            assert!(!path.contains('/'), "Only top-level files are supported.");
+            let blob = self.repository.blob(content.as_bytes()).unwrap();
+            builder.insert(path, blob, 0o100_644).unwrap();
+        }
+        let tree = self.repository.find_tree(builder.write().unwrap()).unwrap();
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        self.repository
+            .commit(
+                None,
+                &signature,
+                &signature,
+                message,
+                &tree,
+                parents.iter().collect::<Vec<_>>().as_slice(),
+            )
+            .unwrap()
+    }
+
+    pub fn branch(&self, name: &str, id: Oid) {
+        self.repository.branch(name, &self.find(id), true).unwrap();
+    }
+
+    pub fn tip(&self, name: &str) -> Oid {
+        self.repository
+            .revparse_single(&format!("refs/heads/{}", name))
+            .unwrap()
+            .id()
+    }
+
+    pub fn files(&self, id: Oid) -> BTreeMap<String, String> {
+        let tree = self.find(id).tree().unwrap();
+        tree.iter()
+            .map(|entry| {
+                let blob = self.repository.find_blob(entry.id()).unwrap();
+                (
+                    entry.name().unwrap().to_string(),
+                    String::from_utf8(blob.content().to_vec()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// Summaries of the commits reachable from `from` but not from `hide`, oldest first.
+    pub fn log(&self, from: Oid, hide: Option<Oid>) -> Vec<String> {
+        let mut revwalk = self.repository.revwalk().unwrap();
+        revwalk
+            .set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)
+            .unwrap();
+        revwalk.push(from).unwrap();
+        if let Some(hide) = hide {
+            revwalk.hide(hide).unwrap();
+        }
+        revwalk
+            .map(|id| self.find(id.unwrap()).summary().unwrap().to_string())
+            .collect()
+    }
+
+    pub fn is_ancestor(&self, ancestor: Oid, descendant: Oid) -> bool {
+        ancestor == descendant
+            || self
+                .repository
+                .graph_descendant_of(descendant, ancestor)
+                .unwrap()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.repository.path()
+    }
+
+    /// Resolves local branches by name.
+    pub fn tips(&self, names: &[&str]) -> Vec<Tip<'_>> {
+        names
+            .iter()
+            .map(|name| Tip::resolve_branch(&self.repository, name).unwrap())
+            .collect()
+    }
+
+    /// Arguments for a verified backport of `tips` without backups, to be adjusted with struct update syntax.
+    pub fn args<'a, E: Editor>(&'a self, tips: &'a [Tip<'a>], editor: E) -> BackportArgs<'a, E> {
+        BackportArgs {
+            repository: &self.repository,
+            backup: false,
+            branches: tips,
+            siblings: &[],
+            merge_strategy: Default::default(),
+            messages: Default::default(),
+            parent_preference: Default::default(),
+            verify: true,
+            autosquash: false,
+            cherry_pick: false,
+            empty: Default::default(),
+            update_refs: false,
+            update_tags: false,
+            editor,
+        }
+    }
+
+    /// Runs a backport without backups, moving the commits with the given summaries to the given branch indices.
+    pub fn backport(&self, branches: &[&str], moves: &[(&str, usize)]) -> Result<Vec<Oid>, Error> {
+        self.backport_tips(&self.tips(branches), moves)
+    }
+
+    pub fn backport_tips(&self, tips: &[Tip], moves: &[(&str, usize)]) -> Result<Vec<Oid>, Error> {
+        backport(self.args(tips, Moves(moves)))
+    }
+}
+
+/// Moves the commits with the given summaries to the given branch indices. Problems fail the test.
+pub struct Moves<'a>(pub &'a [(&'a str, usize)]);
+
+impl Editor for Moves<'_> {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        assert_eq!(problems, &[]);
+        for (summary, branch_index) in self.0 {
+            let commit = plan
+                .commits
+                .iter_mut()
+                .find(|c| c.commit.summary() == Some(summary))
+                .unwrap_or_else(|| panic!("{} was not collected", summary));
+            commit.branch_index = *branch_index;
+        }
+        Ok(EditOutcome::Apply)
+    }
+}
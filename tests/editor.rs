@@ -0,0 +1,633 @@
+mod common;
+
+use {
+    common::TestRepo,
+    git2::Oid,
+    git_backport::{
+        backport,
+        conflicts::ConflictPredictor,
+        editor::{Accept, EditOutcome, Editor, EditorError, Plan, Problem},
+        rules::{Matcher, Rule, Rules},
+        todo::{edit_message, TodoFile},
+        BackportArgs, Error, Fold, FoldKind,
+    },
+};
+
+/// `release`: base; `feature`: f1 -> (f2 | s1) -> m, with s1 forked from f1.
+fn loop_repo() -> (TestRepo, Oid) {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let f1 = repo.chain(Some(release), &["f1"]);
+    let f2 = repo.chain(Some(f1), &["f2"]);
+    let s1 = repo.chain(Some(f1), &["s1"]);
+    let merge = repo.merge(f2, s1, "m");
+    repo.branch("release", release);
+    repo.branch("feature", merge);
+    (repo, merge)
+}
+
+fn run<E: Editor>(repo: &TestRepo, editor: E) -> Result<Vec<Oid>, Error> {
+    run_autosquash(repo, false, editor)
+}
+
+fn run_autosquash<E: Editor>(
+    repo: &TestRepo,
+    autosquash: bool,
+    editor: E,
+) -> Result<Vec<Oid>, Error> {
+    let tips = repo.tips(&["feature", "release"]);
+    backport(BackportArgs {
+        autosquash,
+        ..repo.args(&tips, editor)
+    })
+}
+
+fn find<'a>(plan: &'a mut Plan, summary: &str) -> &'a mut usize {
+    &mut plan
+        .commits
+        .iter_mut()
+        .find(|c| c.commit.summary() == Some(summary))
+        .unwrap()
+        .branch_index
+}
+
+/// Moves only the merge first, then fixes the plan once the problem is reported.
+struct Fixup {
+    calls: Vec<Vec<Problem>>,
+}
+
+impl Editor for Fixup {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.calls.push(problems.to_vec());
+        *find(plan, "m") = 1;
+        if !problems.is_empty() {
+            for summary in &["f1", "f2"] {
+                *find(plan, summary) = 1;
+            }
+        }
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn invalid_plan_is_edited_again() {
+    let (repo, merge) = loop_repo();
+    let mut editor = Fixup { calls: vec![] };
+
+    run(&repo, &mut editor).unwrap();
+
+    assert_eq!(editor.calls.len(), 2);
+    assert!(editor.calls[0].is_empty());
+    assert_eq!(editor.calls[1].len(), 1);
+    assert_eq!(editor.calls[1][0].commit, merge);
+    assert_eq!(repo.files(repo.tip("release")), repo.files(merge));
+}
+
+struct Cancel;
+
+impl Editor for Cancel {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        *find(plan, "f1") = 1;
+        Ok(EditOutcome::Cancel)
+    }
+}
+
+#[test]
+fn cancel_modifies_nothing() {
+    let (repo, merge) = loop_repo();
+    let release = repo.tip("release");
+
+    match run(&repo, Cancel) {
+        Err(Error::Canceled) => (),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert_eq!(repo.tip("feature"), merge);
+    assert_eq!(repo.tip("release"), release);
+}
+
+#[test]
+fn todo_file_moves_commits() {
+    let (repo, merge) = loop_repo();
+    let editor = TodoFile {
+        path: repo.path().join("BACKPORT_TODO"),
+        editor: r"sed -i -e 's/^feature \([0-9a-f]*\) f1$/release \1 f1/'".to_string(),
+    };
+
+    run(&repo, editor).unwrap();
+
+    assert_eq!(repo.log(repo.tip("release"), None), ["base", "f1"]);
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(merge));
+    assert!(!repo.path().join("BACKPORT_TODO").exists());
+}
+
+#[test]
+fn empty_todo_file_cancels() {
+    let (repo, _) = loop_repo();
+    let editor = TodoFile {
+        path: repo.path().join("BACKPORT_TODO"),
+        editor: "truncate -s 0".to_string(),
+    };
+
+    assert!(matches!(run(&repo, editor), Err(Error::Canceled)));
+}
+
+#[test]
+fn rules_move_matching_commits() {
+    let (repo, merge) = loop_repo();
+    let rules = Rules {
+        rules: vec![
+            Rule {
+                matcher: Matcher::Path("f1.".to_string()),
+                branch: "release".to_string(),
+            },
+            Rule {
+                matcher: Matcher::Message("f2".to_string()),
+                branch: "unknown".to_string(),
+            },
+        ],
+    };
+
+    run(&repo, rules).unwrap();
+
+    assert_eq!(repo.log(repo.tip("release"), None), ["base", "f1"]);
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(merge));
+}
+
+/// Records the predicted conflicts after moving f2 to `release`.
+struct Predict(Vec<Option<usize>>);
+
+impl Editor for Predict {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        let predictor = ConflictPredictor::new(plan.repository)?;
+        assert_eq!(predictor.predict(plan)?, [None, None]);
+        *find(plan, "f2") = 1;
+        self.0 = predictor.predict(plan)?;
+        Ok(EditOutcome::Cancel)
+    }
+}
+
+#[test]
+fn conflicts_are_predicted() {
+    // f2 changes what f1 wrote, so it doesn't apply to release without f1.
+    let repo = TestRepo::new();
+    let release = repo.commit(None, "base", &[("file", "a\n")]);
+    let f1 = repo.commit(Some(release), "f1", &[("file", "b\n")]);
+    let f2 = repo.commit(Some(f1), "f2", &[("file", "c\n")]);
+    repo.branch("release", release);
+    repo.branch("feature", f2);
+    let objects = repo.path().join("objects");
+    let count = || count_files(&objects);
+    let before = count();
+
+    let mut editor = Predict(vec![]);
+    assert!(matches!(run(&repo, &mut editor), Err(Error::Canceled)));
+
+    // Newest first: f2 conflicts on release, f1 is fine.
+    assert_eq!(editor.0, [Some(1), None]);
+    assert_eq!(count(), before, "Predicting must not write objects");
+}
+
+fn count_files(path: &std::path::Path) -> usize {
+    std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                count_files(&entry.path())
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+/// Records the assignments it's handed, by summary.
+struct Record(Vec<(String, usize)>);
+
+impl Editor for Record {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.0 = plan
+            .commits
+            .iter()
+            .map(|c| (c.commit.summary().unwrap().to_string(), c.branch_index))
+            .collect();
+        Ok(EditOutcome::Cancel)
+    }
+}
+
+#[test]
+fn configured_rules_preassign_commits() {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let develop = repo.chain(Some(release), &["d1"]);
+    let ci = repo.commit(Some(develop), "Fix CI", &[("ci.yml", "")]);
+    let docs = repo.commit(
+        Some(ci),
+        "Fix typo\n\nBackport-Area: docs\nReviewed-by: Someone",
+        &[("readme", "")],
+    );
+    let tagged = repo.commit(Some(docs), "[backport:develop] Fix bug", &[("bug", "")]);
+    let feature = repo.chain(Some(tagged), &["f1"]);
+    repo.branch("release", release);
+    repo.branch("develop", develop);
+    repo.branch("feature", feature);
+    {
+        let mut config = repo.repository.config().unwrap();
+        config.set_str("backport.rule.ci.path", "ci.").unwrap();
+        config.set_str("backport.rule.ci.branch", "*").unwrap();
+        config
+            .set_str("backport.rule.docs.trailer", "backport-area: docs")
+            .unwrap();
+        config.set_str("backport.rule.docs.branch", "rel*").unwrap();
+        config
+            .set_str("backport.rule.tagged.message", "[backport:develop]")
+            .unwrap();
+        config
+            .set_str("backport.rule.tagged.branch", "develop")
+            .unwrap();
+    }
+    let rules = Rules::load(&repo.repository.config().unwrap()).unwrap();
+    assert_eq!(rules.rules.len(), 3);
+
+    let tips = repo.tips(&["feature", "develop", "release"]);
+    let mut record = Record(vec![]);
+    let result = backport(repo.args(&tips, rules.before(&mut record)));
+
+    assert!(matches!(result, Err(Error::Canceled)));
+    let expected = [
+        ("f1", 0),
+        ("[backport:develop] Fix bug", 1),
+        ("Fix typo", 2),
+        ("Fix CI", 2),
+        ("d1", 1),
+    ];
+    assert_eq!(
+        record.0,
+        expected
+            .iter()
+            .map(|(summary, branch_index)| (summary.to_string(), *branch_index))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn rules_keep_folded_commits_with_their_targets() {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let a = repo.commit(Some(release), "a", &[("a.txt", "a")]);
+    let fixup = repo.commit(Some(a), "fixup! a", &[("ci.yml", "")]);
+    let b = repo.commit(Some(fixup), "b", &[("ci.yml", "b")]);
+    repo.branch("release", release);
+    repo.branch("feature", b);
+    let mut config = repo.repository.config().unwrap();
+    config.set_str("backport.rule.ci.path", "ci.").unwrap();
+    config
+        .set_str("backport.rule.ci.branch", "release")
+        .unwrap();
+    let rules = Rules::load(&config).unwrap();
+
+    let mut record = Record(vec![]);
+    let result = run_autosquash(&repo, true, rules.before(&mut record));
+
+    assert!(matches!(result, Err(Error::Canceled)));
+    let expected = [("b", 1), ("fixup! a", 0), ("a", 0)];
+    assert_eq!(
+        record.0,
+        expected
+            .iter()
+            .map(|(summary, branch_index)| (summary.to_string(), *branch_index))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn rules_without_branch_are_rejected() {
+    let repo = TestRepo::new();
+    repo.repository
+        .config()
+        .unwrap()
+        .set_str("backport.rule.orphan.path", "src/")
+        .unwrap();
+
+    match Rules::load(&repo.repository.config().unwrap()) {
+        Err(Error::InvalidConfig { key, .. }) => assert_eq!(key, "backport.rule.orphan.branch"),
+        _ => panic!("Expected a configuration error"),
+    }
+}
+
+#[test]
+fn backport_to_trailers_preassign_commits() {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let f1 = repo.commit(
+        Some(release),
+        "f1\n\nBackport-To: release\nBackport-To: unknown",
+        &[("f1", "")],
+    );
+    let f2 = repo.commit(Some(f1), "f2\n\nbackport-to: feature", &[("f2", "")]);
+    let f3 = repo.commit(Some(f2), "f3\n\nBackport-To: unknown", &[("f3", "")]);
+    repo.branch("release", release);
+    repo.branch("feature", f3);
+
+    run(&repo, Accept).unwrap();
+
+    assert_eq!(repo.log(repo.tip("release"), None), ["base", "f1"]);
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(f3));
+}
+
+/// Moves the first hunk of `mixed` to `release`, keeping the rest on `feature`.
+struct Split;
+
+impl Editor for Split {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        let position = plan
+            .commits
+            .iter()
+            .position(|c| c.commit.summary() == Some("mixed"))
+            .unwrap();
+        let mut hunks = plan.hunks(position)?;
+        assert_eq!(
+            hunks
+                .iter()
+                .map(|h| h.path.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["new", "numbers", "numbers"]
+        );
+        hunks[1].branch_index = 1;
+        plan.split(position, &hunks)?;
+        assert_eq!(plan.commits.len(), 3);
+        assert_eq!(plan.origin(position), plan.origin(position + 1));
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn split_commits_are_applied_to_several_branches() {
+    let repo = TestRepo::new();
+    let numbers = (1..=20).map(|n| n.to_string()).collect::<Vec<_>>();
+    let release = repo.commit(None, "base", &[("numbers", &numbers.join("\n"))]);
+    let mut changed = numbers.clone();
+    changed[1] = "two".to_string();
+    changed[19] = "twenty".to_string();
+    let mixed = repo.commit(
+        Some(release),
+        "mixed",
+        &[("numbers", &changed.join("\n")), ("new", "new\n")],
+    );
+    let feature = repo.chain(Some(mixed), &["f1"]);
+    repo.branch("release", release);
+    repo.branch("feature", feature);
+
+    run(&repo, Split).unwrap();
+
+    let mut expected = numbers;
+    expected[1] = "two".to_string();
+    let release = repo.tip("release");
+    assert_eq!(repo.log(release, None), ["base", "mixed"]);
+    assert_eq!(repo.files(release)["numbers"], expected.join("\n"));
+    assert!(!repo.files(release).contains_key("new"));
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(feature));
+    assert!(repo.is_ancestor(release, repo.tip("feature")));
+}
+
+/// `release`: base; `feature`: a -> b -> fixup! a -> squash! b.
+fn fold_repo() -> (TestRepo, Oid) {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let b = repo.chain(Some(release), &["a", "b"]);
+    let fixup = repo.commit(Some(b), "fixup! a", &[("a.txt", "fixed a")]);
+    let squash = repo.commit(
+        Some(fixup),
+        "squash! b\n\nAlso fix b.",
+        &[("b.txt", "fixed b")],
+    );
+    repo.branch("release", release);
+    repo.branch("feature", squash);
+    (repo, squash)
+}
+
+/// Moves everything to `release`, after checking what autosquash did.
+struct MoveAll;
+
+impl Editor for MoveAll {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        let folds = plan
+            .commits
+            .iter()
+            .map(|c| c.fold.map(|fold| fold.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            folds,
+            [Some(FoldKind::Squash), Some(FoldKind::Fixup), None, None]
+        );
+        assert_eq!(
+            plan.commits[0].fold.unwrap().into,
+            plan.commits[2].commit.id()
+        );
+        assert_eq!(
+            plan.commits[1].fold.unwrap().into,
+            plan.commits[3].commit.id()
+        );
+        for commit in &mut plan.commits {
+            commit.branch_index = 1;
+        }
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn autosquash_folds_commits() {
+    let (repo, feature) = fold_repo();
+
+    run_autosquash(&repo, true, MoveAll).unwrap();
+
+    let release = repo.tip("release");
+    assert_eq!(repo.log(release, None), ["base", "a", "b"]);
+    assert_eq!(repo.files(release), repo.files(feature));
+    assert_eq!(repo.find(release).message(), Some("b\n\nAlso fix b.\n"));
+    assert_eq!(repo.find(release).parent(0).unwrap().message(), Some("a"));
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(feature));
+}
+
+#[test]
+fn conflicting_folds_are_reported() {
+    // The fixup changes what b wrote after a, so it doesn't apply right after a.
+    let repo = TestRepo::new();
+    let release = repo.commit(None, "base", &[("file", "0\n")]);
+    let a = repo.commit(Some(release), "a", &[("file", "a\n")]);
+    let b = repo.commit(Some(a), "b", &[("file", "b\n")]);
+    let fixup = repo.commit(Some(b), "fixup! a", &[("file", "fixed\n")]);
+    repo.branch("release", release);
+    repo.branch("feature", fixup);
+
+    match run_autosquash(&repo, true, Accept) {
+        Err(Error::Conflict { from, into }) => {
+            assert_eq!(from, fixup.to_string());
+            assert_eq!(into, "feature");
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert_eq!(repo.tip("feature"), fixup);
+}
+
+#[test]
+fn todo_file_folds_commits() {
+    let (repo, feature) = fold_repo();
+    let editor = TodoFile {
+        path: repo.path().join("BACKPORT_TODO"),
+        editor: r"sed -i -e 's/^feature \([0-9a-f]*\) \(fixup\|squash\)!/fixup \1 \2!/'"
+            .to_string(),
+    };
+
+    run(&repo, editor).unwrap();
+
+    // Each fixup lands in the commit right above it, i.e. `b`.
+    let tip = repo.tip("feature");
+    assert_eq!(repo.log(tip, None), ["base", "a", "b"]);
+    assert_eq!(repo.files(tip), repo.files(feature));
+    assert_eq!(repo.find(tip).message(), Some("b"));
+}
+
+/// Folds `b` into `a` and moves only `a`.
+struct FoldAcrossBranches(Vec<Vec<Problem>>);
+
+impl Editor for FoldAcrossBranches {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        self.0.push(problems.to_vec());
+        if !problems.is_empty() {
+            return Ok(EditOutcome::Cancel);
+        }
+        let a = plan.commits[3].commit.id();
+        plan.commits[2].fold = Some(Fold {
+            kind: FoldKind::Fixup,
+            into: a,
+        });
+        plan.commits[3].branch_index = 1;
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn folds_across_branches_are_problems() {
+    let (repo, _) = fold_repo();
+    let mut editor = FoldAcrossBranches(vec![]);
+
+    assert!(matches!(run(&repo, &mut editor), Err(Error::Canceled)));
+
+    assert_eq!(editor.0.len(), 2);
+    assert_eq!(editor.0[1].len(), 1);
+    assert!(editor.0[1][0].message.ends_with("which is on release"));
+}
+
+/// Rewords both commits, moving only the first to `release`.
+struct Reword;
+
+impl Editor for Reword {
+    fn edit(&mut self, plan: &mut Plan, _: &[Problem]) -> Result<EditOutcome, EditorError> {
+        for commit in &mut plan.commits {
+            commit.message = Some(format!("{} (reworded)\n", commit.message()));
+        }
+        *find(plan, "f1") = 1;
+        Ok(EditOutcome::Apply)
+    }
+}
+
+#[test]
+fn reworded_commits_get_new_messages() {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let feature = repo.chain(Some(release), &["f1", "f2"]);
+    repo.branch("release", release);
+    repo.branch("feature", feature);
+
+    run(&repo, Reword).unwrap();
+
+    assert_eq!(
+        repo.log(repo.tip("release"), None),
+        ["base", "f1 (reworded)"]
+    );
+    assert_eq!(
+        repo.log(repo.tip("feature"), None),
+        ["base", "f1 (reworded)", "f2 (reworded)"]
+    );
+    assert_eq!(repo.files(repo.tip("feature")), repo.files(feature));
+}
+
+fn reword_repo() -> TestRepo {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let feature = repo.chain(Some(release), &["f1", "f2"]);
+    repo.branch("release", release);
+    repo.branch("feature", feature);
+    repo
+}
+
+#[test]
+fn todo_file_rewords_commits() {
+    let repo = reword_repo();
+    // The same editor edits the todo file and then f1's message.
+    let editor = TodoFile {
+        path: repo.path().join("BACKPORT_TODO"),
+        editor:
+            r"sed -i -e 's/^feature \([0-9a-f]*\) f1$/r release \1 f1/' -e 's/^f1$/f1 (reworded)/'"
+                .to_string(),
+    };
+
+    run(&repo, editor).unwrap();
+
+    assert_eq!(
+        repo.log(repo.tip("release"), None),
+        ["base", "f1 (reworded)"]
+    );
+    assert_eq!(
+        repo.log(repo.tip("feature"), None),
+        ["base", "f1 (reworded)", "f2"]
+    );
+    assert!(!repo.path().join("BACKPORT_EDITMSG").exists());
+}
+
+/// Rewords f2, then lets the todo file edit the plan.
+struct RewordThen(TodoFile);
+
+impl Editor for RewordThen {
+    fn edit(&mut self, plan: &mut Plan, problems: &[Problem]) -> Result<EditOutcome, EditorError> {
+        let f2 = plan
+            .commits
+            .iter_mut()
+            .find(|c| c.commit.summary() == Some("f2"))
+            .unwrap();
+        f2.message = Some("f2 (reworded)\n".to_string());
+        self.0.edit(plan, problems)
+    }
+}
+
+#[test]
+fn todo_file_keeps_rewords() {
+    let repo = reword_repo();
+    // Fails unless the reword is listed, and keeps the file as is otherwise.
+    let editor = RewordThen(TodoFile {
+        path: repo.path().join("BACKPORT_TODO"),
+        editor: r"grep -q '^reword feature [0-9a-f]* f2 (reworded)$'".to_string(),
+    });
+
+    run(&repo, editor).unwrap();
+
+    assert_eq!(
+        repo.log(repo.tip("feature"), None),
+        ["base", "f1", "f2 (reworded)"]
+    );
+}
+
+#[test]
+fn edited_messages_drop_comments() {
+    let repo = TestRepo::new();
+    let path = repo.path().join("BACKPORT_EDITMSG");
+    let edit = |editor: &str| edit_message(editor, &path, "Subject\n\nBody\n", "Help").unwrap();
+
+    assert_eq!(
+        edit(r"sed -i -e '1s/$/ [release]/'"),
+        Some("Subject [release]\n\nBody\n".to_string())
+    );
+    assert_eq!(edit("truncate -s 0"), None);
+    assert!(!path.exists());
+}
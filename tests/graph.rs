@@ -0,0 +1,202 @@
+mod common;
+
+use {
+    common::TestRepo,
+    git2::{Oid, Sort},
+    git_backport::graph::CommitGraph,
+    std::{collections::HashMap, convert::TryInto, fs, path::PathBuf, process::Command},
+};
+
+/// Runs `git commit-graph write --reachable` with `args` on the repository.
+fn write_commit_graph(repo: &TestRepo, args: &[&str]) {
+    let status = Command::new("git")
+        .args(["commit-graph", "write", "--reachable"])
+        .args(args)
+        .env("GIT_DIR", repo.repository.path())
+        .status()
+        .expect("Failed to run git");
+    assert!(status.success());
+}
+
+/// `main`: A linear history with a merge and an octopus merge of three side chains.
+fn history(repo: &TestRepo) -> Oid {
+    let base = repo.chain(None, &["base", "a"]);
+    let side = repo.chain(Some(base), &["s1"]);
+    let main = repo.chain(Some(base), &["b"]);
+    let merge = repo.merge(main, side, "merge");
+
+    let parents = [
+        repo.chain(Some(merge), &["o1"]),
+        repo.chain(Some(merge), &["o2"]),
+        repo.chain(Some(merge), &["o3", "o4"]),
+    ]
+    .iter()
+    .map(|&id| repo.find(id))
+    .collect::<Vec<_>>();
+    let signature = repo.repository.signature().unwrap();
+    let octopus = repo
+        .repository
+        .commit(
+            None,
+            &signature,
+            &signature,
+            "octopus",
+            &parents[0].tree().unwrap(),
+            parents.iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap();
+    let tip = repo.chain(Some(octopus), &["c"]);
+    repo.branch("main", tip);
+    tip
+}
+
+/// All commits reachable from `tip` with their parents, parents first.
+fn commits(repo: &TestRepo, tip: Oid) -> Vec<(Oid, Vec<Oid>)> {
+    let mut revwalk = repo.repository.revwalk().unwrap();
+    revwalk
+        .set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)
+        .unwrap();
+    revwalk.push(tip).unwrap();
+    revwalk
+        .map(|id| {
+            let commit = repo.find(id.unwrap());
+            (commit.id(), commit.parent_ids().collect())
+        })
+        .collect()
+}
+
+/// Compares the parents and generations read through the commit-graph file, if it's `used`, with those from libgit2.
+fn assert_matches_libgit2(repo: &TestRepo, tip: Oid, used: bool) {
+    let mut graph = CommitGraph::new(&repo.repository);
+    assert_eq!(graph.has_commit_graph_file(), used);
+
+    let mut generations = HashMap::new();
+    for (id, parents) in commits(repo, tip) {
+        let generation = parents
+            .iter()
+            .map(|p| generations[p] + 1)
+            .max()
+            .unwrap_or(1);
+        generations.insert(id, generation);
+
+        assert_eq!(graph.parents(id).unwrap(), parents.as_slice());
+        assert_eq!(graph.generation(id).unwrap(), generation);
+    }
+}
+
+#[test]
+fn commit_graph_file_matches_libgit2() {
+    let repo = TestRepo::new();
+    let tip = history(&repo);
+    write_commit_graph(&repo, &[]);
+
+    assert_matches_libgit2(&repo, tip, true);
+}
+
+#[test]
+fn generations_above_a_floor_match_libgit2() {
+    let repo = TestRepo::new();
+    let tip = history(&repo);
+    let commits = commits(&repo, tip);
+    let is_or_has_ancestor = |descendant: Oid, ancestor: Oid| {
+        descendant == ancestor
+            || repo
+                .repository
+                .graph_descendant_of(descendant, ancestor)
+                .unwrap()
+    };
+
+    // Each floor is tried with a fresh graph, since generations are cached.
+    for &(floor, _) in &commits {
+        let mut graph = CommitGraph::with_floor(&repo.repository, floor);
+        assert!(!graph.has_commit_graph_file());
+        for (id, parents) in &commits {
+            let generation = graph.generation(*id).unwrap();
+            assert_eq!(generation == 0, is_or_has_ancestor(floor, *id));
+            for parent in parents {
+                assert!(generation == 0 || graph.generation(*parent).unwrap() < generation);
+            }
+        }
+        for &(descendant, _) in &commits {
+            for &(ancestor, _) in &commits {
+                assert_eq!(
+                    graph.is_or_has_ancestor(descendant, ancestor).unwrap(),
+                    is_or_has_ancestor(descendant, ancestor)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn split_commit_graph_files_match_libgit2() {
+    let repo = TestRepo::new();
+    let tip = history(&repo);
+    write_commit_graph(&repo, &["--split"]);
+    let tip = repo.chain(Some(tip), &["d1", "d2"]);
+    repo.branch("main", tip);
+    write_commit_graph(&repo, &["--split=no-merge"]);
+
+    let chain = repo
+        .repository
+        .path()
+        .join("objects/info/commit-graphs/commit-graph-chain");
+    assert_eq!(fs::read_to_string(chain).unwrap().lines().count(), 2);
+    assert_matches_libgit2(&repo, tip, true);
+}
+
+#[test]
+fn corrupt_commit_graph_files_are_ignored() {
+    let repo = TestRepo::new();
+    let tip = history(&repo);
+    write_commit_graph(&repo, &[]);
+    let path: PathBuf = repo.repository.path().join("objects/info/commit-graph");
+    let data = fs::read(&path).unwrap();
+    let commits = commits(&repo, tip);
+
+    // Corrupt parents could form cycles, which must not keep generation numbers from being computed.
+    let query = || {
+        let mut graph = CommitGraph::new(&repo.repository);
+        for (id, _) in &commits {
+            graph.parents(*id).ok();
+            graph.generation(*id).ok();
+        }
+    };
+    for length in 0..data.len() {
+        fs::write(&path, &data[..length]).unwrap();
+        query();
+    }
+    for position in 0..data.len() {
+        let mut corrupt = data.clone();
+        corrupt[position] ^= 0xff;
+        fs::write(&path, &corrupt).unwrap();
+        query();
+    }
+}
+
+#[test]
+fn cyclic_commit_graph_files_are_ignored() {
+    let repo = TestRepo::new();
+    let tip = history(&repo);
+    write_commit_graph(&repo, &[]);
+    let path: PathBuf = repo.repository.path().join("objects/info/commit-graph");
+    let mut data = fs::read(&path).unwrap();
+
+    // Makes each commit its own first parent, without a generation number.
+    let chunk_count = data[6] as usize;
+    let commit_data = (0..chunk_count)
+        .map(|i| 8 + i * 12)
+        .find(|&entry| &data[entry..entry + 4] == b"CDAT")
+        .map(|entry| u64::from_be_bytes(data[entry + 4..entry + 12].try_into().unwrap()) as usize)
+        .unwrap();
+    let count = commits(&repo, tip).len();
+    for position in 0..count {
+        let record = commit_data + position * 36;
+        data[record + 20..record + 24].copy_from_slice(&(position as u32).to_be_bytes());
+        data[record + 28..record + 31].fill(0);
+        data[record + 31] &= 0b11;
+    }
+    fs::write(&path, &data).unwrap();
+
+    assert_matches_libgit2(&repo, tip, false);
+}
@@ -0,0 +1,38 @@
+//! Separate from the other tests, since interrupts affect the whole process.
+
+mod common;
+
+use {
+    common::TestRepo,
+    git_backport::{interrupt, Error},
+};
+
+#[test]
+fn interrupt_cancels_before_updating_branches() {
+    let repo = TestRepo::new();
+    let release = repo.chain(None, &["base"]);
+    let feature = repo.chain(Some(release), &["f1"]);
+    repo.branch("release", release);
+    repo.branch("feature", feature);
+
+    assert!(interrupt::request());
+
+    assert!(matches!(
+        repo.backport(&["feature", "release"], &[("f1", 1)]),
+        Err(Error::Canceled)
+    ));
+    assert_eq!(repo.tip("release"), release);
+    assert_eq!(repo.tip("feature"), feature);
+    assert!(!interrupt::requested_while_updating());
+
+    // Later backports in the same process start over.
+    let updated = repo
+        .backport(&["feature", "release"], &[("f1", 1)])
+        .unwrap();
+    assert_eq!(repo.tip("release"), updated[1]);
+    assert!(interrupt::request());
+    assert!(matches!(
+        repo.backport(&["feature", "release"], &[]),
+        Err(Error::Canceled)
+    ));
+}
@@ -0,0 +1,125 @@
+//! Generated histories: Every commit adds its own file, so each rewritten branch's tree is known exactly.
+
+mod common;
+
+use {
+    common::TestRepo,
+    proptest::prelude::*,
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+#[derive(Debug, Clone)]
+enum Step {
+    Commit,
+    /// A side chain of this many commits, forked from the current tip and merged back.
+    Loop(usize),
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    prop_oneof![3 => Just(Step::Commit), 1 => (1..=2usize).prop_map(Step::Loop)]
+}
+
+/// Segments of the branches more junior than the most senior one, from most junior to most senior,
+/// plus a raw branch index per collected commit.
+fn history() -> impl Strategy<Value = (Vec<Vec<Step>>, Vec<usize>)> {
+    (2..=4usize)
+        .prop_flat_map(|branch_count| {
+            prop::collection::vec(prop::collection::vec(step(), 0..=3), branch_count - 1)
+        })
+        .prop_flat_map(|segments| {
+            let commit_count = segments.iter().map(Vec::len).sum::<usize>();
+            let branch_count = segments.len() + 1;
+            (
+                Just(segments),
+                prop::collection::vec(0..branch_count, commit_count),
+            )
+        })
+}
+
+struct Collected {
+    summary: String,
+    files: Vec<String>,
+    /// The chain commit the side chain of a merge was forked from.
+    fork: Option<String>,
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    #[test]
+    fn trees_follow_assignments((segments, raw_indices) in history()) {
+        let repo = TestRepo::new();
+        let branch_count = segments.len() + 1;
+        let names = (0..branch_count).map(|i| format!("b{}", i)).collect::<Vec<_>>();
+
+        let mut tip = repo.chain(None, &["base"]);
+        repo.branch(&names[branch_count - 1], tip);
+        let mut tip_summary = None;
+        let mut collected = vec![];
+        let mut counter = 0;
+        for (branch_index, segment) in segments.iter().enumerate().rev() {
+            for step in segment {
+                counter += 1;
+                match step {
+                    Step::Commit => {
+                        let summary = format!("c{}", counter);
+                        tip = repo.chain(Some(tip), &[&summary]);
+                        collected.push(Collected {
+                            files: vec![format!("{}.txt", summary)],
+                            summary: summary.clone(),
+                            fork: None,
+                        });
+                        tip_summary = Some(summary);
+                    }
+                    Step::Loop(length) => {
+                        let side = (0..*length)
+                            .map(|i| format!("s{}-{}", counter, i))
+                            .collect::<Vec<_>>();
+                        let side_tip = repo.chain(
+                            Some(tip),
+                            &side.iter().map(String::as_str).collect::<Vec<_>>(),
+                        );
+                        let summary = format!("m{}", counter);
+                        tip = repo.merge(tip, side_tip, &summary);
+                        collected.push(Collected {
+                            files: side.iter().map(|s| format!("{}.txt", s)).collect(),
+                            summary: summary.clone(),
+                            fork: tip_summary.clone(),
+                        });
+                        tip_summary = Some(summary);
+                    }
+                }
+            }
+            repo.branch(&names[branch_index], tip);
+        }
+
+        // A merge can't live on a more senior branch than the commit its side chain forked from.
+        let mut assigned = BTreeMap::new();
+        for (commit, &raw) in collected.iter().zip(raw_indices.iter()) {
+            let branch_index = match &commit.fork {
+                Some(fork) => raw.min(assigned[fork]),
+                None => raw,
+            };
+            assigned.insert(commit.summary.clone(), branch_index);
+        }
+        let moves = assigned
+            .iter()
+            .map(|(summary, &branch_index)| (summary.as_str(), branch_index))
+            .collect::<Vec<_>>();
+
+        repo.backport(&names.iter().map(String::as_str).collect::<Vec<_>>(), &moves)
+            .unwrap();
+
+        for (branch_index, name) in names.iter().enumerate() {
+            let mut expected = BTreeSet::new();
+            expected.insert("base.txt".to_string());
+            for commit in &collected {
+                if assigned[&commit.summary] >= branch_index {
+                    expected.extend(commit.files.iter().cloned());
+                }
+            }
+            let actual = repo.files(repo.tip(name)).into_keys().collect::<BTreeSet<_>>();
+            prop_assert_eq!(actual, expected, "branch {}", name);
+        }
+    }
+}